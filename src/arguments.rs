@@ -0,0 +1,322 @@
+use std::collections::{HashMap, HashSet};
+
+use super::error::MkError;
+use super::graph;
+use super::node::{Node, Parameter};
+use super::segments_scanner::SegmentsScanner;
+use super::source::{Locator, MappedText, Sources};
+use super::variables;
+
+/// Target of the command line with the `name=value` arguments following it
+#[derive(Debug, PartialEq)]
+pub struct Target<'a> {
+    pub name: &'a str,
+    pub arguments: Vec<(&'a str, &'a str)>,
+}
+
+/// Name and value of a `name=value` argument
+fn argument(value: &str) -> Option<(&str, &str)> {
+    let (name, value) = value.split_once('=')?;
+    variables::is_name(name).then_some((name, value))
+}
+
+/// Targets with the arguments given after each of them
+pub fn split<'a>(values: &[&'a str]) -> Result<Vec<Target<'a>>, MkError<'static>> {
+    let mut targets: Vec<Target> = Vec::new();
+    for value in values {
+        match (argument(value), targets.last_mut()) {
+            (Some(argument), Some(target)) => target.arguments.push(argument),
+            (Some(_), None) => {
+                return Err(MkError::ArgumentWithoutTarget {
+                    argument: value.to_string(),
+                });
+            }
+            (None, _) => targets.push(Target {
+                name: value,
+                arguments: Vec::new(),
+            }),
+        }
+    }
+    Ok(targets)
+}
+
+/// Parameters of each segment from all of its definitions
+/// with the offset of the first definition
+fn declared<'t>(nodes: &[Node<'t>]) -> Vec<(&'t str, usize, Vec<Parameter<'t>>)> {
+    let mut declared: Vec<(&str, usize, Vec<Parameter>)> = Vec::new();
+    for node in nodes {
+        let Node::Segment { name, span, .. } = node else {
+            continue;
+        };
+        let i = match declared.iter().position(|(n, ..)| n == name) {
+            Some(i) => i,
+            None => {
+                declared.push((name, span.start, Vec::new()));
+                declared.len() - 1
+            }
+        };
+        for parameter in node.parameters() {
+            if !declared[i].2.iter().any(|p| p.name == parameter.name) {
+                declared[i].2.push(parameter);
+            }
+        }
+    }
+    declared
+}
+
+fn set(environment: &mut Vec<(String, String)>, name: &str, value: &str) {
+    match environment.iter_mut().find(|(n, _)| n == name) {
+        Some(variable) => variable.1 = value.to_string(),
+        None => environment.push((name.to_string(), value.to_string())),
+    }
+}
+
+/// Segments the targets select, none if they don't resolve as the run reports it
+fn selection<'a>(
+    nodes: &[Node<'a>],
+    targets: &[&'a str],
+    options: &graph::Options,
+) -> HashSet<&'a str> {
+    graph::resolve_segments(nodes, targets, options)
+        .map(|resolved| resolved.segments)
+        .unwrap_or_default()
+}
+
+/// Content with `{{param}}` replaced in the segments declaring the parameter,
+/// and the environment of the scripts holding the arguments of the targets.
+/// Arguments of a target named exactly must match the parameters of the target
+/// or of the dependencies it selects, and every selected segment must be given
+/// its required parameters. A parameter takes the argument given to its segment,
+/// then the one given to another target, then its own default.
+pub fn apply<'a>(
+    content: MappedText,
+    sources: &'a Sources,
+    targets: &[Target],
+    options: &graph::Options,
+) -> Result<(MappedText, Vec<(String, String)>), MkError<'a>> {
+    let nodes: Vec<_> = SegmentsScanner::new(&content.content).collect();
+    let declared = declared(&nodes);
+    let parameters = |name: &str| {
+        declared
+            .iter()
+            .find(|(n, ..)| *n == name)
+            .map(|(_, offset, parameters)| (*offset, parameters.as_slice()))
+    };
+    let declares = |segment: &str, name: &str| {
+        parameters(segment).is_some_and(|(_, parameters)| parameters.iter().any(|p| p.name == name))
+    };
+    let locator = Locator::new(&content.content, &content.map, sources);
+    let mut given = Vec::new();
+    for target in targets {
+        for (name, value) in &target.arguments {
+            set(&mut given, name, value);
+        }
+    }
+    let mut environment = given.clone();
+    for target in targets {
+        // arguments of a pattern or an unknown target are only passed to the scripts
+        let Some((offset, parameters)) = parameters(target.name) else {
+            continue;
+        };
+        let selected = selection(&nodes, &[target.name], options);
+        if let Some((name, _)) = target.arguments.iter().find(|(name, _)| {
+            !declares(target.name, name) && !selected.iter().any(|s| declares(s, name))
+        }) {
+            return Err(MkError::UnknownArgument {
+                target: target.name.to_string(),
+                name: name.to_string(),
+                location: locator.locate(offset),
+            });
+        }
+        for parameter in parameters {
+            if let Some(default) = parameter.default
+                && !environment.iter().any(|(n, _)| n == parameter.name)
+            {
+                set(&mut environment, parameter.name, default);
+            }
+        }
+    }
+    let names: Vec<&str> = targets.iter().map(|t| t.name).collect();
+    let selected = selection(&nodes, &names, options);
+    for (name, offset, parameters) in &declared {
+        if !selected.contains(name) {
+            continue;
+        }
+        if let Some(parameter) = parameters
+            .iter()
+            .find(|p| p.default.is_none() && !given.iter().any(|(n, _)| n == p.name))
+        {
+            return Err(MkError::MissingArgument {
+                target: name.to_string(),
+                name: parameter.name.to_string(),
+                location: locator.locate(*offset),
+            });
+        }
+    }
+    if declared
+        .iter()
+        .all(|(_, _, parameters)| parameters.is_empty())
+    {
+        return Ok((content, environment));
+    }
+    let mut result = String::with_capacity(content.content.len());
+    for node in &nodes {
+        let (name, text, span) = match node {
+            Node::Segment {
+                name,
+                content,
+                span,
+                ..
+            } => (*name, *content, span),
+            Node::Content { span, .. } => {
+                result.push_str(&content.content[span.start..span.end]);
+                continue;
+            }
+        };
+        result.push_str(&content.content[span.start..span.end - text.len()]);
+        let own = targets.iter().find(|t| t.name == name);
+        let values: HashMap<&str, String> = node
+            .parameters()
+            .into_iter()
+            .filter_map(|parameter| {
+                let value = own
+                    .and_then(|t| t.arguments.iter().find(|(n, _)| *n == parameter.name))
+                    .map(|(_, value)| *value)
+                    .or_else(|| {
+                        given
+                            .iter()
+                            .find(|(n, _)| n == parameter.name)
+                            .map(|(_, value)| value.as_str())
+                    })
+                    .or(parameter.default)?;
+                Some((parameter.name, value.to_string()))
+            })
+            .collect();
+        result.push_str(&variables::substitute(text, &values));
+    }
+    Ok((
+        MappedText {
+            content: result,
+            map: content.map,
+        },
+        environment,
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::syntax::fixtures;
+
+    fn apply_text(
+        content: &str,
+        values: &[&str],
+    ) -> Result<(String, Vec<(String, String)>), String> {
+        let sources = fixtures::sources(content);
+        let content = fixtures::desugar(&sources);
+        let targets = split(values).map_err(|e| e.to_string())?;
+        apply(content, &sources, &targets, &Default::default())
+            .map(|(content, environment)| (content.content, environment))
+            .map_err(|e| e.to_string())
+    }
+
+    fn variables(pairs: &[(&str, &str)]) -> Vec<(String, String)> {
+        pairs
+            .iter()
+            .map(|(n, v)| (n.to_string(), v.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn should_parse_parameter() {
+        assert_eq!(
+            Parameter::parse(" @param env=staging  target environment"),
+            Some(Parameter {
+                name: "env",
+                default: Some("staging"),
+                description: "target environment",
+            })
+        );
+        assert_eq!(
+            Parameter::parse(" @param region"),
+            Some(Parameter {
+                name: "region",
+                default: None,
+                description: "",
+            })
+        );
+        assert_eq!(Parameter::parse(" @parameters x"), None);
+        assert_eq!(Parameter::parse(" @param 1x"), None);
+    }
+
+    #[test]
+    fn should_split_targets_and_arguments() {
+        assert_eq!(
+            split(&["deploy", "env=prod", "tag=a=b", "build", "g/*"]).unwrap(),
+            [
+                Target {
+                    name: "deploy",
+                    arguments: vec![("env", "prod"), ("tag", "a=b")],
+                },
+                Target {
+                    name: "build",
+                    arguments: vec![],
+                },
+                Target {
+                    name: "g/*",
+                    arguments: vec![],
+                },
+            ]
+        );
+        assert_eq!(
+            split(&["env=prod", "deploy"]).unwrap_err().to_string(),
+            "argument `env=prod` is not preceded by a target"
+        );
+    }
+
+    #[test]
+    fn should_substitute_arguments_and_defaults() {
+        let content = "deploy: build\n    # @param env=staging target environment\n    # @param region\n    ./deploy {{env}} {{region}} {{other}}\nbuild:\n    # @param env=dev\n    make {{env}}\n";
+        assert_eq!(
+            apply_text(content, &["deploy", "region=eu"]).unwrap(),
+            (
+                "deploy: build\n    # @param env=staging target environment\n    # @param region\n    ./deploy staging eu {{other}}\nbuild:\n    # @param env=dev\n    make dev\n".to_string(),
+                variables(&[("region", "eu"), ("env", "staging")])
+            )
+        );
+        assert_eq!(
+            apply_text(content, &["build"]).unwrap().1,
+            variables(&[("env", "dev")])
+        );
+    }
+
+    #[test]
+    fn should_validate_arguments() {
+        let content = "deploy:\n    # @param region\n    ./deploy {{region}}\n";
+        assert_eq!(
+            apply_text(content, &["deploy"]).unwrap_err(),
+            "`deploy` requires the argument `region=...`"
+        );
+        assert_eq!(
+            apply_text(content, &["deploy", "region=eu", "env=prod"]).unwrap_err(),
+            "`deploy` has no parameter `env`"
+        );
+    }
+
+    #[test]
+    fn should_validate_arguments_of_dependencies() {
+        let content = "deploy:\n    # @param region\n    echo deploying {{region}}\nall: deploy\n";
+        assert_eq!(
+            apply_text(content, &["all"]).unwrap_err(),
+            "`deploy` requires the argument `region=...`"
+        );
+        assert_eq!(
+            apply_text(content, &["all", "region=eu"]).unwrap().0,
+            "deploy:\n    # @param region\n    echo deploying eu\nall: deploy\n"
+        );
+        assert_eq!(
+            apply_text(content, &["all", "env=prod"]).unwrap_err(),
+            "`all` has no parameter `env`"
+        );
+    }
+}
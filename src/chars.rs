@@ -7,10 +7,7 @@ pub fn is_new_line(&(_, c): &(usize, char)) -> bool {
 }
 
 pub fn find_new_line_index(content: &str) -> Option<usize> {
-    content
-        .char_indices()
-        .find(is_new_line)
-        .and_then(|(i, _)| Some(i))
+    content.char_indices().find(is_new_line).map(|(i, _)| i)
 }
 
 pub fn is_not_indentation(&(_, c): &(usize, char)) -> bool {
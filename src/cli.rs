@@ -1,22 +1,31 @@
 use std::env;
 use std::fs;
 use std::io::{IsTerminal, Read};
+use std::num::NonZeroUsize;
 
 use clap::ArgMatches;
 use clap::ValueHint;
+use clap::builder::BoolishValueParser;
 use clap::{Arg, ArgAction, Command, value_parser};
 use clap_complete::engine::{ArgValueCompleter, CompletionCandidate};
 use clap_complete::env::CompleteEnv;
 use clap_complete::{Generator, Shell, generate};
 use glob::glob;
 
+use super::error::{EXIT_CODES, MkError};
+use super::graph::Order;
+use super::include;
 use super::node::Node;
 use super::printer::Printer;
+use super::script::Delivery;
 use super::segments_scanner::SegmentsScanner;
+use super::source::{SourceFile, Sources};
 use super::syntax;
+use super::variables;
 
 const META: &str = include_str!(concat!(env!("CARGO_MANIFEST_DIR"), "/Cargo.toml"));
-const DEFAULT_INPUT: &str = "[Mm]kfile*";
+pub const DEFAULT_INPUT: &str = "[Mm]kfile*";
+const STDIN_NAME: &str = "<stdin>";
 
 struct Meta {
     name: &'static str,
@@ -27,67 +36,134 @@ struct Meta {
 fn parse_meta() -> Option<Meta> {
     let meta: &'static toml::Table = Box::leak(Box::new(toml::from_str(META).ok()?));
     let package = meta.get("package")?.as_table()?;
-    return Some(Meta {
+    Some(Meta {
         name: package.get("name")?.as_str()?,
         description: package.get("description")?.as_str()?,
         version: package.get("version")?.as_str()?,
-    });
+    })
 }
 
-pub fn read_content_from_files(pattern: &str) -> Result<String, Box<dyn std::error::Error>> {
+/// Sorted names of the input files matching the pattern
+pub fn input_files(pattern: &str) -> Result<Vec<String>, MkError<'static>> {
+    let mut filenames: Vec<_> = glob(pattern)
+        .map_err(|e| MkError::BadGlob {
+            pattern: pattern.to_string(),
+            reason: e.msg.to_string(),
+            location: None,
+        })?
+        .filter_map(Result::ok)
+        .map(|path| path.to_string_lossy().into_owned())
+        .collect();
+    filenames.sort();
+    Ok(filenames)
+}
+
+/// Sources of the input files and the files they include
+pub fn read_sources(
+    filenames: Vec<String>,
+    read: impl Fn(&str) -> std::io::Result<String>,
+) -> Result<Sources, MkError<'static>> {
     let mut files = Vec::new();
-    let mut filenames: Vec<_> = glob(pattern).unwrap().filter_map(Result::ok).collect();
+    for name in filenames {
+        match read(&name) {
+            Ok(content) => files.push(SourceFile { name, content }),
+            Err(source) => {
+                return Err(MkError::Io {
+                    path: Some(name),
+                    source,
+                });
+            }
+        }
+    }
+    let mut sources = Sources::new(files);
+    include::read(&mut sources, read);
+    Ok(sources)
+}
+
+pub fn read_sources_from_files(pattern: &str) -> Result<Sources, MkError<'static>> {
+    let filenames = input_files(pattern)?;
     if filenames.is_empty() {
         let mut cwd = env::current_dir()?;
         if cwd.pop() {
             env::set_current_dir(cwd)?;
-            return read_content_from_files(pattern);
+            return read_sources_from_files(pattern);
         }
-        return Err("no mkfiles found".into());
+        return Err(std::io::Error::new(std::io::ErrorKind::NotFound, "no mkfiles found").into());
     }
-    filenames.sort();
-    for path in filenames {
-        match fs::read_to_string(path) {
-            Ok(content) => files.push(content),
-            Err(e) => return Err(e.into()),
-        }
-    }
-    Ok(files.join("\n"))
+    read_sources(filenames, |name| fs::read_to_string(name))
+}
+
+/// Includes of the standard input are relative to the current directory
+pub fn read_sources_from_stdin() -> Result<Sources, std::io::Error> {
+    let mut content = String::new();
+    std::io::stdin().read_to_string(&mut content)?;
+    let mut sources = Sources::new(vec![SourceFile {
+        name: STDIN_NAME.to_string(),
+        content,
+    }]);
+    include::read(&mut sources, |name| fs::read_to_string(name));
+    Ok(sources)
 }
 
 fn targets_completer(current: &std::ffi::OsStr) -> Vec<CompletionCandidate> {
     let Some(current) = current.to_str() else {
         return vec![];
     };
-    let content = {
-        let mut stdin = std::io::stdin();
-        if stdin.is_terminal() {
-            read_content_from_files(DEFAULT_INPUT).unwrap_or_default()
-        } else {
-            let mut input = String::new();
-            stdin.read_to_string(&mut input).unwrap_or_default();
-            input
-        }
+    let sources = if std::io::stdin().is_terminal() {
+        read_sources_from_files(DEFAULT_INPUT).ok()
+    } else {
+        read_sources_from_stdin().ok()
+    };
+    let Some(sources) = sources else {
+        return vec![];
     };
-    let content = syntax::desugar(content.as_str());
-    SegmentsScanner::new(content.as_str())
-        .filter_map(|node| match node {
-            Node::Content(_) => None,
-            Node::Segment { name, .. } => {
-                if name.starts_with(current) {
-                    Some(CompletionCandidate::new(name))
-                } else {
-                    None
-                }
+    let Ok(content) = syntax::desugar(&sources, &Default::default()) else {
+        return vec![];
+    };
+    let content = content.content;
+    let mut candidates = Vec::new();
+    let mut parameters = Vec::new();
+    for node in SegmentsScanner::new(content.as_str()) {
+        let Node::Segment { name, .. } = node else {
+            continue;
+        };
+        if name.starts_with(current) {
+            candidates.push(CompletionCandidate::new(name));
+        }
+        // arguments are offered for any target, the completer doesn't see the previous words
+        for parameter in node.parameters() {
+            let argument = format!("{}=", parameter.name);
+            if argument.starts_with(current) && !parameters.contains(&argument) {
+                let help = match parameter.description {
+                    "" => name.to_string(),
+                    description => format!("{name}: {description}"),
+                };
+                parameters.push(argument.clone());
+                candidates.push(CompletionCandidate::new(argument).help(Some(help.into())));
             }
-        })
-        .collect()
+        }
+    }
+    candidates
+}
+
+/// Exit codes of mk, followed by the ones of the scripts
+fn exit_codes() -> String {
+    let mut help = String::from("Exit codes:\n");
+    for (code, reason) in EXIT_CODES {
+        help.push_str(&format!("  {code:<5}{reason}\n"));
+    }
+    help.push_str(
+        "\nA failed script exits mk with its own code, which can be one of the above,\n\
+         or with 128 + N when the signal N terminated it",
+    );
+    help
 }
 
 fn build_cli(meta: &Meta) -> Command {
     Command::new(meta.name)
         .version(meta.version)
         .about(meta.description)
+        .after_help(exit_codes())
         .arg(
             Arg::new("target")
                 .help("target segment(s)")
@@ -102,18 +178,153 @@ fn build_cli(meta: &Meta) -> Command {
                 .default_value(DEFAULT_INPUT)
                 .value_hint(ValueHint::AnyPath),
         )
+        .arg(
+            Arg::new("define")
+                .help("Set the variable NAME, overriding its definition and the environment")
+                .short('D')
+                .long("define")
+                .value_name("NAME=value")
+                .action(ArgAction::Append)
+                .value_parser(variables::parse_override),
+        )
+        .arg(
+            Arg::new("default")
+                .help("Target run when none is given and the mkfiles mark no default")
+                .long("default")
+                .env("MK_DEFAULT")
+                .value_name("TARGET"),
+        )
         .arg(
             Arg::new("printer")
                 .short('P')
                 .long("printer")
                 .value_parser(value_parser!(Printer)),
         )
+        .arg(
+            Arg::new("strict")
+                .help("Fail on undefined dependencies (default for the executor)")
+                .long("strict")
+                .action(ArgAction::SetTrue)
+                .conflicts_with("lenient"),
+        )
+        .arg(
+            Arg::new("lenient")
+                .help("Warn about undefined dependencies")
+                .long("lenient")
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("abbreviations")
+                .help("Accept an unambiguous prefix of each component of a target name, e.g. a/p/b")
+                .long("abbreviations")
+                .env("MK_ABBREVIATIONS")
+                .value_parser(BoolishValueParser::new())
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("no-deps")
+                .help("Select the targets without their dependencies")
+                .long("no-deps")
+                .action(ArgAction::SetTrue)
+                .conflicts_with_all(["only-deps", "depth"]),
+        )
+        .arg(
+            Arg::new("only-deps")
+                .help("Select the dependencies of the targets without the targets")
+                .long("only-deps")
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("depth")
+                .help("Select dependencies at most N levels below the targets")
+                .long("depth")
+                .value_name("N")
+                .value_parser(value_parser!(usize)),
+        )
+        .arg(
+            Arg::new("explain")
+                .help("Print why each segment is selected to the standard error")
+                .long("explain")
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("order")
+                .help("Order of the segments in the output")
+                .long("order")
+                .default_value("file")
+                .value_parser(value_parser!(Order)),
+        )
+        .arg(
+            Arg::new("jobs")
+                .help("Execute each segment as a separate job, N jobs at a time")
+                .short('j')
+                .long("jobs")
+                .value_name("N")
+                .value_parser(value_parser!(NonZeroUsize)),
+        )
+        .arg(
+            Arg::new("script")
+                .help("How the executed script is passed to its interpreter")
+                .long("script")
+                .default_value("file")
+                .value_parser(value_parser!(Delivery)),
+        )
+        .arg(
+            Arg::new("shell")
+                .help("Interpreter of scripts without a #! line")
+                .long("shell")
+                .env("MK_SHELL")
+                .default_value("sh")
+                .value_hint(ValueHint::CommandName),
+        )
         .arg(
             Arg::new("generate-completions")
                 .long("generate-completions")
                 .action(ArgAction::Set)
                 .value_parser(value_parser!(Shell)),
         )
+        .arg(
+            Arg::new("lsp")
+                .help("Serve the language server protocol over stdio")
+                .long("lsp")
+                .action(ArgAction::SetTrue)
+                .conflicts_with_all(["target", "format", "lint"]),
+        )
+        .arg(
+            Arg::new("format")
+                .help("Format the mkfiles in place, or the standard input to the standard output")
+                .long("format")
+                .action(ArgAction::SetTrue)
+                .conflicts_with("target"),
+        )
+        .arg(
+            Arg::new("check")
+                .help("Fail listing the files that are not formatted instead")
+                .long("check")
+                .action(ArgAction::SetTrue)
+                .requires("format"),
+        )
+        .arg(
+            Arg::new("sort-dependencies")
+                .help("Sort the dependencies of each header")
+                .long("sort-dependencies")
+                .action(ArgAction::SetTrue)
+                .requires("format"),
+        )
+        .arg(
+            Arg::new("lint")
+                .help("Check the mkfiles for likely mistakes")
+                .long("lint")
+                .action(ArgAction::SetTrue)
+                .conflicts_with_all(["target", "format"]),
+        )
+        .arg(
+            Arg::new("deny")
+                .help("Report the problems as errors and fail")
+                .long("deny")
+                .action(ArgAction::SetTrue)
+                .requires("lint"),
+        )
         .arg(
             Arg::new("arguments")
                 .help("Arguments passed to the executable script")
@@ -126,7 +337,7 @@ fn print_completions<G: Generator>(g: G, cmd: &mut Command) {
     generate(g, cmd, cmd.get_name().to_string(), &mut std::io::stdout());
 }
 
-pub fn get_matches() -> Result<Option<ArgMatches>, Box<dyn std::error::Error>> {
+pub fn get_matches() -> Option<ArgMatches> {
     let meta = parse_meta().unwrap();
     CompleteEnv::with_factory(|| build_cli(&meta)).complete();
     let matches = build_cli(&meta).get_matches();
@@ -134,7 +345,17 @@ pub fn get_matches() -> Result<Option<ArgMatches>, Box<dyn std::error::Error>> {
         let mut cmd = build_cli(&meta);
         eprintln!("Generating completion file for {generator}...");
         print_completions(generator, &mut cmd);
-        return Ok(None);
+        return None;
+    }
+    Some(matches)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn should_build_cli() {
+        build_cli(&parse_meta().unwrap()).debug_assert();
     }
-    Ok(Some(matches))
 }
@@ -1,12 +1,18 @@
+use super::node::Dependency;
+use super::source::Span;
+
 pub struct DependenciesCollector<'a> {
     content: &'a str,
+    offset: usize,
     word_begin: isize,
 }
 
 impl<'a> DependenciesCollector<'a> {
-    pub fn new(content: &'a str) -> Self {
+    /// `offset` is the position of `content` in the scanned text
+    pub fn new(content: &'a str, offset: usize) -> Self {
         Self {
             content,
+            offset,
             word_begin: -1,
         }
     }
@@ -17,14 +23,18 @@ impl<'a> DependenciesCollector<'a> {
         }
     }
 
-    fn collect_if_started(&mut self, end: usize, deps: &mut Vec<&'a str>) {
+    fn collect_if_started(&mut self, end: usize, deps: &mut Vec<Dependency<'a>>) {
         if self.word_begin != -1 {
-            deps.push(&self.content[self.word_begin as usize..end]);
+            let start = self.word_begin as usize;
+            deps.push(Dependency::new(
+                &self.content[start..end],
+                Span::new(start, end).shift(self.offset),
+            ));
             self.word_begin = -1;
         }
     }
 
-    pub fn collect(&mut self) -> (usize, Vec<&'a str>) {
+    pub fn collect(&mut self) -> (usize, Vec<Dependency<'a>>) {
         let mut deps = Vec::new();
         for (i, c) in self.content.char_indices() {
             if c == '\n' {
@@ -0,0 +1,511 @@
+use std::os::unix::process::ExitStatusExt;
+use std::process::ExitStatus;
+use std::{fmt, io};
+
+use super::chars::{contains_glob_pattern_symbols, is_valid_segment_name_char};
+use super::source::{Location, Sources};
+
+// Exit codes of mk's own failures are kept out of the range
+// commonly used by scripts
+pub const UNKNOWN_TARGET_EXIT_CODE: u8 = 64;
+pub const BAD_GLOB_EXIT_CODE: u8 = 65;
+pub const BAD_GROUP_INDENTATION_EXIT_CODE: u8 = 66;
+pub const DEPENDENCY_CYCLE_EXIT_CODE: u8 = 67;
+pub const UNDEFINED_DEPENDENCY_EXIT_CODE: u8 = 68;
+pub const INCLUDE_CYCLE_EXIT_CODE: u8 = 69;
+pub const FORMAT_CHANGES_OUTPUT_EXIT_CODE: u8 = 70;
+pub const AMBIGUOUS_TARGET_EXIT_CODE: u8 = 71;
+pub const UNKNOWN_ARGUMENT_EXIT_CODE: u8 = 72;
+pub const MISSING_ARGUMENT_EXIT_CODE: u8 = 73;
+pub const IO_EXIT_CODE: u8 = 74;
+pub const ARGUMENT_WITHOUT_TARGET_EXIT_CODE: u8 = 75;
+pub const BAD_INCLUDE_EXIT_CODE: u8 = 76;
+pub const LINT_FAILED_EXIT_CODE: u8 = 77;
+pub const UNFORMATTED_EXIT_CODE: u8 = 78;
+pub const MULTILINE_VARIABLE_EXIT_CODE: u8 = 79;
+pub const CANNOT_EXECUTE_EXIT_CODE: u8 = 126;
+pub const COMMAND_NOT_FOUND_EXIT_CODE: u8 = 127;
+
+/// Exit codes of mk's own failures with their reason, for the help
+pub const EXIT_CODES: &[(u8, &str)] = &[
+    (UNKNOWN_TARGET_EXIT_CODE, "Unknown target"),
+    (BAD_GLOB_EXIT_CODE, "Bad glob pattern"),
+    (
+        BAD_GROUP_INDENTATION_EXIT_CODE,
+        "Line after a group is partially indented",
+    ),
+    (DEPENDENCY_CYCLE_EXIT_CODE, "Dependency cycle"),
+    (
+        UNDEFINED_DEPENDENCY_EXIT_CODE,
+        "Undefined dependency (strict mode)",
+    ),
+    (INCLUDE_CYCLE_EXIT_CODE, "Include cycle"),
+    (
+        FORMAT_CHANGES_OUTPUT_EXIT_CODE,
+        "Formatting would change the output",
+    ),
+    (AMBIGUOUS_TARGET_EXIT_CODE, "Ambiguous abbreviated target"),
+    (
+        UNKNOWN_ARGUMENT_EXIT_CODE,
+        "Argument of an undeclared parameter",
+    ),
+    (
+        MISSING_ARGUMENT_EXIT_CODE,
+        "Required parameter without an argument",
+    ),
+    (IO_EXIT_CODE, "I/O error"),
+    (
+        ARGUMENT_WITHOUT_TARGET_EXIT_CODE,
+        "Argument before any target",
+    ),
+    (BAD_INCLUDE_EXIT_CODE, "An included file can't be read"),
+    (LINT_FAILED_EXIT_CODE, "Lint problems with --deny"),
+    (UNFORMATTED_EXIT_CODE, "Unformatted files with --check"),
+    (
+        MULTILINE_VARIABLE_EXIT_CODE,
+        "Multi-line variable from the environment",
+    ),
+    (CANNOT_EXECUTE_EXIT_CODE, "The script cannot be executed"),
+    (
+        COMMAND_NOT_FOUND_EXIT_CODE,
+        "The script interpreter is not found",
+    ),
+];
+
+#[derive(Debug)]
+pub enum MkError<'a> {
+    UnknownTarget {
+        target: String,
+        /// Closest segment names
+        suggestions: Vec<String>,
+        /// The target names a group without own content
+        group: bool,
+    },
+    /// Argument of a target that doesn't declare the parameter
+    UnknownArgument {
+        target: String,
+        name: String,
+        location: Location<'a>,
+    },
+    /// Required parameter of a target given no argument
+    MissingArgument {
+        target: String,
+        name: String,
+        location: Location<'a>,
+    },
+    /// `name=value` given before any target
+    ArgumentWithoutTarget {
+        argument: String,
+    },
+    /// Abbreviated target matching several segments
+    AmbiguousTarget {
+        target: String,
+        candidates: Vec<String>,
+    },
+    BadGlob {
+        pattern: String,
+        reason: String,
+        location: Option<Location<'a>>,
+    },
+    BadGroupIndentation {
+        group: String,
+        location: Location<'a>,
+    },
+    DependencyCycle {
+        path: Vec<String>,
+        // dependencies of the segments on the cycle, one per edge
+        locations: Vec<Location<'a>>,
+    },
+    UndefinedDependency {
+        segment: String,
+        dependency: String,
+        location: Location<'a>,
+    },
+    Io {
+        path: Option<String>,
+        source: io::Error,
+    },
+    /// Included file could not be read
+    BadInclude {
+        path: String,
+        reason: String,
+        location: Location<'a>,
+    },
+    /// Environment value of a variable with a new line
+    MultilineVariable {
+        name: String,
+        location: Location<'a>,
+    },
+    IncludeCycle {
+        path: Vec<String>,
+        // the include directive closing the cycle
+        location: Location<'a>,
+    },
+    ExecutionFailure {
+        program: String,
+        source: io::Error,
+    },
+    /// Script exited unsuccessfully, `segment` is set for jobs
+    ScriptFailed {
+        segment: Option<String>,
+        status: ExitStatus,
+    },
+    Interrupted {
+        signal: i32,
+    },
+    /// Interactive target picker closed without a choice
+    SelectionCancelled,
+    /// Formatting would change the output of `target`,
+    /// `None` stands for the common content or a broken file
+    FormatChangesOutput {
+        file: String,
+        target: Option<String>,
+    },
+    /// Files that `mk --format --check` would change
+    Unformatted {
+        files: Vec<String>,
+    },
+    UnreachableSegment {
+        segment: String,
+        location: Location<'a>,
+    },
+    /// Line written like a header with a name the scanner doesn't accept
+    InvalidHeader {
+        name: String,
+        location: Location<'a>,
+    },
+    MixedIndentation {
+        segment: String,
+        // of the first line mixing them
+        width: usize,
+        location: Location<'a>,
+    },
+    UnmatchedPushd {
+        group: String,
+        location: Location<'a>,
+    },
+    /// `mk --lint --deny` found problems
+    LintFailed {
+        count: usize,
+    },
+}
+
+impl<'a> MkError<'a> {
+    pub fn exit_code(&self) -> u8 {
+        match self {
+            Self::UnknownTarget { .. } => UNKNOWN_TARGET_EXIT_CODE,
+            Self::AmbiguousTarget { .. } => AMBIGUOUS_TARGET_EXIT_CODE,
+            Self::UnknownArgument { .. } => UNKNOWN_ARGUMENT_EXIT_CODE,
+            Self::MissingArgument { .. } => MISSING_ARGUMENT_EXIT_CODE,
+            Self::ArgumentWithoutTarget { .. } => ARGUMENT_WITHOUT_TARGET_EXIT_CODE,
+            Self::BadGlob { .. } => BAD_GLOB_EXIT_CODE,
+            Self::BadGroupIndentation { .. } => BAD_GROUP_INDENTATION_EXIT_CODE,
+            Self::DependencyCycle { .. } => DEPENDENCY_CYCLE_EXIT_CODE,
+            Self::UndefinedDependency { .. } => UNDEFINED_DEPENDENCY_EXIT_CODE,
+            Self::Io { .. } => IO_EXIT_CODE,
+            Self::BadInclude { .. } => BAD_INCLUDE_EXIT_CODE,
+            Self::MultilineVariable { .. } => MULTILINE_VARIABLE_EXIT_CODE,
+            Self::IncludeCycle { .. } => INCLUDE_CYCLE_EXIT_CODE,
+            Self::ExecutionFailure { source, .. } => {
+                if source.kind() == io::ErrorKind::NotFound {
+                    COMMAND_NOT_FOUND_EXIT_CODE
+                } else {
+                    CANNOT_EXECUTE_EXIT_CODE
+                }
+            }
+            // the status of a killed script is reported like a shell does
+            Self::ScriptFailed { status, .. } => match (status.code(), status.signal()) {
+                (Some(code), _) => code.clamp(1, 255) as u8,
+                (None, Some(signal)) => signal_exit_code(signal),
+                (None, None) => 1,
+            },
+            Self::Interrupted { signal } => signal_exit_code(*signal),
+            Self::SelectionCancelled => signal_exit_code(libc::SIGINT),
+            Self::FormatChangesOutput { .. } => FORMAT_CHANGES_OUTPUT_EXIT_CODE,
+            Self::Unformatted { .. } => UNFORMATTED_EXIT_CODE,
+            // lint problems fail only as warnings denied together
+            Self::LintFailed { .. }
+            | Self::UnreachableSegment { .. }
+            | Self::InvalidHeader { .. }
+            | Self::MixedIndentation { .. }
+            | Self::UnmatchedPushd { .. } => LINT_FAILED_EXIT_CODE,
+        }
+    }
+
+    /// Locations with the width of the highlighted text,
+    /// `None` highlights a segment or dependency name
+    pub fn locations(&self) -> Vec<(Location<'a>, Option<usize>)> {
+        match self {
+            Self::BadGlob {
+                pattern, location, ..
+            } => location
+                .iter()
+                .map(|l| (*l, Some(pattern.chars().count())))
+                .collect(),
+            Self::BadGroupIndentation { location, .. } => vec![(*location, Some(1))],
+            Self::DependencyCycle { locations, .. } => {
+                locations.iter().map(|l| (*l, None)).collect()
+            }
+            Self::UndefinedDependency { location, .. }
+            | Self::UnknownArgument { location, .. }
+            | Self::MissingArgument { location, .. }
+            | Self::UnreachableSegment { location, .. }
+            | Self::BadInclude { location, .. }
+            | Self::MultilineVariable { location, .. }
+            | Self::IncludeCycle { location, .. } => vec![(*location, None)],
+            Self::InvalidHeader { name, location } => {
+                vec![(*location, Some(name.chars().count() + 1))]
+            }
+            Self::MixedIndentation {
+                width, location, ..
+            } => vec![(*location, Some(*width))],
+            Self::UnmatchedPushd { location, .. } => vec![(*location, Some("pushd".len()))],
+            _ => Vec::new(),
+        }
+    }
+
+    /// Message followed by the locations and the source excerpts if known
+    pub fn render(&self, sources: Option<&Sources>) -> String {
+        self.render_as("error", sources)
+    }
+
+    pub fn render_warning(&self, sources: Option<&Sources>) -> String {
+        self.render_as("warning", sources)
+    }
+
+    fn render_as(&self, level: &str, sources: Option<&Sources>) -> String {
+        let mut out = format!("{level}: {}\n", self);
+        for (location, width) in self.locations() {
+            render_excerpt(&mut out, location, width, sources);
+        }
+        out
+    }
+}
+
+/// Width of the highlighted text starting from the zero-based column,
+/// a name ends at `:` or whitespace
+pub fn highlight_width(text: &str, column: usize, width: Option<usize>) -> usize {
+    width.unwrap_or_else(|| {
+        text.chars()
+            .skip(column)
+            .take_while(|c| *c != ':' && !c.is_whitespace())
+            .count()
+    })
+}
+
+fn signal_exit_code(signal: i32) -> u8 {
+    (128 + signal).clamp(1, 255) as u8
+}
+
+fn render_excerpt(
+    out: &mut String,
+    location: Location,
+    width: Option<usize>,
+    sources: Option<&Sources>,
+) {
+    let line = location.line.to_string();
+    let pad = " ".repeat(line.len());
+    out.push_str(&format!("{pad}--> {location}\n"));
+    let Some(text) = sources.and_then(|s| s.line(location.file, location.line)) else {
+        return;
+    };
+    let column = location.column.saturating_sub(1);
+    let marker: String = text
+        .chars()
+        .take(column)
+        .map(|c| if c == '\t' { '\t' } else { ' ' })
+        .collect();
+    let width = highlight_width(text, column, width);
+    out.push_str(&format!("{pad} |\n"));
+    out.push_str(&format!("{line} | {text}\n"));
+    out.push_str(&format!("{pad} | {marker}{}\n", "^".repeat(width.max(1))));
+}
+
+impl fmt::Display for MkError<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::UnknownTarget {
+                target,
+                suggestions,
+                group,
+            } => {
+                write!(f, "target not found: {target}")?;
+                if *group {
+                    let group = target.trim_end_matches('/');
+                    write!(
+                        f,
+                        "; `{group}/` is a group, `{group}/*` selects its segments"
+                    )?;
+                }
+                if let Some((last, rest)) = suggestions.split_last() {
+                    write!(f, "; did you mean ")?;
+                    let rest: Vec<_> = rest.iter().map(|s| format!("`{s}`")).collect();
+                    if !rest.is_empty() {
+                        write!(f, "{} or ", rest.join(", "))?;
+                    }
+                    write!(f, "`{last}`?")?;
+                }
+                Ok(())
+            }
+            Self::AmbiguousTarget { target, candidates } => {
+                write!(
+                    f,
+                    "target `{target}` is ambiguous: {}",
+                    candidates.join(", ")
+                )
+            }
+            Self::UnknownArgument { target, name, .. } => {
+                write!(f, "`{target}` has no parameter `{name}`")
+            }
+            Self::MissingArgument { target, name, .. } => {
+                write!(f, "`{target}` requires the argument `{name}=...`")
+            }
+            Self::ArgumentWithoutTarget { argument } => {
+                write!(f, "argument `{argument}` is not preceded by a target")
+            }
+            Self::BadGlob {
+                pattern, reason, ..
+            } => write!(f, "bad glob pattern `{pattern}`: {reason}"),
+            Self::BadGroupIndentation { group, .. } => write!(
+                f,
+                "indentation does not match the content of the group `{group}`"
+            ),
+            Self::DependencyCycle { path, .. } => {
+                write!(f, "dependency cycle: {}", path.join(" -> "))
+            }
+            Self::UndefinedDependency {
+                segment,
+                dependency,
+                ..
+            } => {
+                if contains_glob_pattern_symbols(dependency) {
+                    write!(
+                        f,
+                        "pattern `{dependency}` of `{segment}` matches no segment"
+                    )
+                } else {
+                    write!(f, "`{segment}` depends on undefined segment `{dependency}`")
+                }
+            }
+            Self::Io {
+                path: Some(path),
+                source,
+            } => write!(f, "{path}: {source}"),
+            Self::Io { path: None, source } => write!(f, "{source}"),
+            Self::BadInclude { path, reason, .. } => write!(f, "cannot include {path}: {reason}"),
+            Self::MultilineVariable { name, .. } => write!(
+                f,
+                "the value of `{name}` from the environment spans several lines"
+            ),
+            Self::IncludeCycle { path, .. } => write!(f, "include cycle: {}", path.join(" -> ")),
+            Self::ExecutionFailure { program, source } => {
+                write!(f, "failed to execute {program}: {source}")
+            }
+            Self::ScriptFailed { segment, status } => {
+                match segment {
+                    Some(segment) => write!(f, "`{segment}`")?,
+                    None => write!(f, "script")?,
+                }
+                match status.signal() {
+                    Some(signal) => write!(f, " was terminated by signal {signal}"),
+                    None => write!(f, " failed with exit code {}", status.code().unwrap_or(1)),
+                }
+            }
+            Self::Interrupted { signal } => write!(f, "interrupted by signal {signal}"),
+            Self::SelectionCancelled => write!(f, "no target selected"),
+            Self::FormatChangesOutput {
+                file,
+                target: Some(target),
+            } => write!(f, "formatting {file} would change the output of `{target}`"),
+            Self::FormatChangesOutput { file, target: None } => {
+                write!(f, "formatting {file} would change its output")
+            }
+            Self::Unformatted { files } => {
+                write!(f, "not formatted: {}", files.join(", "))
+            }
+            Self::UnreachableSegment { segment, .. } => {
+                write!(f, "`{segment}` is not reachable from any documented target")
+            }
+            Self::InvalidHeader { name, .. } => {
+                write!(f, "`{name}:` is not a segment header: ")?;
+                match name.chars().find(|c| !is_valid_segment_name_char(*c)) {
+                    Some(c) => write!(f, "`{c}` is not allowed in segment names"),
+                    None => write!(f, "segment names start with a letter"),
+                }
+            }
+            Self::MixedIndentation { segment, .. } => {
+                write!(f, "`{segment}` mixes tabs and spaces in its indentation")
+            }
+            Self::UnmatchedPushd { group, .. } => {
+                write!(f, "`pushd` in the group `{group}/` has no matching `popd`")
+            }
+            Self::LintFailed { count: 1 } => write!(f, "1 problem found"),
+            Self::LintFailed { count } => write!(f, "{count} problems found"),
+        }
+    }
+}
+
+impl std::error::Error for MkError<'_> {}
+
+impl From<io::Error> for MkError<'_> {
+    fn from(source: io::Error) -> Self {
+        Self::Io { path: None, source }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::syntax::fixtures;
+
+    #[test]
+    fn should_render_excerpt() {
+        let sources = fixtures::sources("foo:\nbar: [a\n");
+        let error = MkError::BadGlob {
+            pattern: "[a".to_string(),
+            reason: "invalid range pattern".to_string(),
+            location: Some(Location {
+                file: "mkfile",
+                line: 2,
+                column: 6,
+            }),
+        };
+        assert_eq!(
+            error.render(Some(&sources)),
+            "error: bad glob pattern `[a`: invalid range pattern
+ --> mkfile:2:6
+  |
+2 | bar: [a
+  |      ^^
+"
+        );
+        assert_eq!(error.exit_code(), BAD_GLOB_EXIT_CODE);
+    }
+
+    #[test]
+    fn should_have_distinct_exit_codes() {
+        for (i, (code, _)) in EXIT_CODES.iter().enumerate() {
+            assert!(!EXIT_CODES[i + 1..].iter().any(|(c, _)| c == code));
+        }
+    }
+
+    #[test]
+    fn should_exit_with_script_status() {
+        let failed = |status| MkError::ScriptFailed {
+            segment: None,
+            status: ExitStatus::from_raw(status),
+        };
+        // wait statuses: the exit code in the second byte, the signal in the first one
+        assert_eq!(failed(3 << 8).exit_code(), 3);
+        assert_eq!(failed(255 << 8).exit_code(), 255);
+        assert_eq!(failed(libc::SIGKILL).exit_code(), 137);
+        assert_eq!(failed(libc::SIGTERM).exit_code(), 143);
+        assert_eq!(
+            MkError::Interrupted {
+                signal: libc::SIGINT
+            }
+            .exit_code(),
+            130
+        );
+    }
+}
@@ -0,0 +1,381 @@
+use signal_hook::consts::{SIGINT, SIGTERM};
+use signal_hook::iterator::{Handle, Signals};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::ffi::OsStr;
+use std::io;
+use std::num::NonZeroUsize;
+use std::process::{Child, ExitStatus};
+use std::sync::atomic::{AtomicI32, Ordering};
+use std::sync::{Arc, Mutex, mpsc};
+use std::thread;
+use std::thread::JoinHandle;
+
+use crate::error::MkError;
+use crate::graph::{self, Order};
+use crate::node::Node;
+use crate::script::{Delivery, Script};
+
+#[derive(Debug, Clone, Default)]
+pub struct Options {
+    /// Run each segment as a separate job, this many at a time
+    pub jobs: Option<NonZeroUsize>,
+    pub delivery: Delivery,
+    /// Interpreter of scripts without a `#!` line
+    pub shell: String,
+    /// Variables set for the scripts, such as the arguments of the targets
+    pub environment: Vec<(String, String)>,
+}
+
+/// Segment executed as a separate script
+#[derive(Debug, PartialEq)]
+pub struct Job<'a> {
+    pub name: &'a str,
+    pub script: String,
+    /// Indices of the jobs that have to succeed first
+    pub dependencies: Vec<usize>,
+}
+
+/// Forwards SIGINT and SIGTERM received by mk to the running scripts
+/// instead of terminating mk, so that it can clean up and report their status
+struct SignalForwarder {
+    children: Arc<Mutex<HashSet<u32>>>,
+    received: Arc<AtomicI32>,
+    handle: Handle,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl SignalForwarder {
+    fn new() -> Result<Self, MkError<'static>> {
+        let mut signals = Signals::new([SIGINT, SIGTERM])?;
+        let children = Arc::new(Mutex::new(HashSet::<u32>::new()));
+        let received = Arc::new(AtomicI32::new(0));
+        let handle = signals.handle();
+        let thread = thread::spawn({
+            let children = children.clone();
+            let received = received.clone();
+            move || {
+                for signal in signals.forever() {
+                    received.store(signal, Ordering::SeqCst);
+                    let from_terminal = signal == SIGINT && is_foreground();
+                    for pid in children.lock().unwrap().iter() {
+                        let pid = *pid as libc::pid_t;
+                        // the terminal interrupts its whole foreground group
+                        if from_terminal && unsafe { libc::getpgid(pid) == libc::getpgrp() } {
+                            continue;
+                        }
+                        unsafe { libc::kill(pid, signal) };
+                    }
+                }
+            }
+        });
+        Ok(Self {
+            children,
+            received,
+            handle,
+            thread: Some(thread),
+        })
+    }
+
+    fn watch(&self, child: &Child) {
+        self.children.lock().unwrap().insert(child.id());
+    }
+
+    /// Status of the exited child, forgotten before it is reaped
+    /// so that its pid can't be reused by a process signaled in its place
+    fn wait(children: &Mutex<HashSet<u32>>, child: &mut Child) -> io::Result<ExitStatus> {
+        let mut info = unsafe { std::mem::zeroed::<libc::siginfo_t>() };
+        let options = libc::WEXITED | libc::WNOWAIT;
+        while unsafe { libc::waitid(libc::P_PID, child.id(), &mut info, options) } < 0 {
+            let error = io::Error::last_os_error();
+            if error.kind() != io::ErrorKind::Interrupted {
+                return Err(error);
+            }
+        }
+        children.lock().unwrap().remove(&child.id());
+        child.wait()
+    }
+
+    /// Last signal received
+    fn received(&self) -> Option<i32> {
+        Some(self.received.load(Ordering::SeqCst)).filter(|s| *s != 0)
+    }
+}
+
+/// Whether mk runs in the foreground process group of its terminal
+fn is_foreground() -> bool {
+    let group = unsafe { libc::getpgrp() };
+    [libc::STDIN_FILENO, libc::STDOUT_FILENO, libc::STDERR_FILENO]
+        .into_iter()
+        .any(|fd| unsafe { libc::tcgetpgrp(fd) } == group)
+}
+
+impl Drop for SignalForwarder {
+    fn drop(&mut self) {
+        self.handle.close();
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+fn check_status<'a>(segment: Option<&str>, status: ExitStatus) -> Result<(), MkError<'a>> {
+    if status.success() {
+        return Ok(());
+    }
+    Err(MkError::ScriptFailed {
+        segment: segment.map(str::to_string),
+        status,
+    })
+}
+
+/// Run the content as a single script
+pub fn run_script<'a, S>(content: &str, options: &Options, args: &[S]) -> Result<(), MkError<'a>>
+where
+    S: AsRef<OsStr>,
+{
+    let forwarder = SignalForwarder::new()?;
+    let script = Script::new(content, options.delivery, &options.shell)?;
+    let mut child = script.spawn(args, &options.environment)?;
+    forwarder.watch(&child);
+    let status = SignalForwarder::wait(&forwarder.children, &mut child).map_err(|source| {
+        MkError::ExecutionFailure {
+            program: script.program(),
+            source,
+        }
+    })?;
+    check_status(None, status)
+}
+
+/// One job per selected segment in the order of definition.
+/// Groups of a job are part of its script, so a group is not a job
+/// of its own when one of its segments is selected.
+pub fn plan<'a>(nodes: &[Node<'a>], segments: &HashSet<&'a str>) -> Vec<Job<'a>> {
+    let mut dependencies: HashMap<&str, Vec<&str>> = HashMap::new();
+    let mut names = Vec::new();
+    for node in nodes {
+        if let Node::Segment {
+            name,
+            dependencies: deps,
+            ..
+        } = node
+            && segments.contains(name)
+        {
+            if !dependencies.contains_key(name) {
+                names.push(*name);
+            }
+            dependencies
+                .entry(name)
+                .or_default()
+                .extend(deps.iter().map(|d| d.name));
+        }
+    }
+    names.retain(|name| !segments.iter().any(|s| graph::is_parent_group(name, s)));
+    let index: HashMap<&str, usize> = names.iter().enumerate().map(|(i, n)| (*n, i)).collect();
+    names
+        .iter()
+        .map(|name| {
+            let groups: HashSet<&str> = segments
+                .iter()
+                .copied()
+                .filter(|s| s == name || graph::is_parent_group(s, name))
+                .collect();
+            // dependencies that are not jobs pass on their own dependencies
+            let mut jobs = Vec::new();
+            let mut visited = groups.clone();
+            let mut stack: Vec<&str> = dependencies[name].clone();
+            while let Some(dep) = stack.pop() {
+                if !visited.insert(dep) || !segments.contains(dep) {
+                    continue;
+                }
+                match index.get(dep) {
+                    Some(i) => jobs.push(*i),
+                    None => stack.extend(dependencies.get(dep).into_iter().flatten()),
+                }
+            }
+            jobs.sort();
+            Job {
+                name,
+                script: graph::render(nodes, &groups, Order::File),
+                dependencies: jobs,
+            }
+        })
+        .collect()
+}
+
+/// Run the jobs after their dependencies with at most `limit` at a time.
+/// No job is started after the first failure or a forwarded signal,
+/// running ones are awaited.
+pub fn run_jobs<'a, S>(
+    jobs: &[Job],
+    limit: NonZeroUsize,
+    options: &Options,
+    args: &[S],
+) -> Result<(), MkError<'a>>
+where
+    S: AsRef<OsStr>,
+{
+    let mut waiting: Vec<usize> = jobs.iter().map(|j| j.dependencies.len()).collect();
+    let mut dependents = vec![Vec::new(); jobs.len()];
+    for (i, job) in jobs.iter().enumerate() {
+        for dep in &job.dependencies {
+            dependents[*dep].push(i);
+        }
+    }
+    let mut ready: VecDeque<usize> = (0..jobs.len()).filter(|i| waiting[*i] == 0).collect();
+    let forwarder = SignalForwarder::new()?;
+    let (sender, receiver) = mpsc::channel();
+    let mut running = 0;
+    let mut failure = None;
+    loop {
+        while failure.is_none() && forwarder.received().is_none() && running < limit.get() {
+            let Some(i) = ready.pop_front() else {
+                break;
+            };
+            let started = Script::new(&jobs[i].script, options.delivery, &options.shell)
+                .and_then(|script| Ok((script.spawn(args, &options.environment)?, script)));
+            match started {
+                Ok((mut child, script)) => {
+                    forwarder.watch(&child);
+                    let sender = sender.clone();
+                    let children = forwarder.children.clone();
+                    thread::spawn(move || {
+                        let status = SignalForwarder::wait(&children, &mut child);
+                        let _ = sender.send((i, script, status));
+                    });
+                    running += 1;
+                }
+                Err(error) => failure = Some(error),
+            }
+        }
+        if running == 0 {
+            break;
+        }
+        let (i, script, status) = receiver.recv().expect("job threads hold a sender");
+        running -= 1;
+        let result = status
+            .map_err(|source| MkError::ExecutionFailure {
+                program: script.program(),
+                source,
+            })
+            .and_then(|status| check_status(Some(jobs[i].name), status));
+        match result {
+            Ok(()) => {
+                for dependent in &dependents[i] {
+                    waiting[*dependent] -= 1;
+                    if waiting[*dependent] == 0 {
+                        ready.push_back(*dependent);
+                    }
+                }
+            }
+            Err(error) => {
+                failure.get_or_insert(error);
+            }
+        }
+    }
+    match (failure, forwarder.received()) {
+        (Some(error), _) => Err(error),
+        (None, Some(signal)) if !ready.is_empty() || waiting.iter().any(|w| *w > 0) => {
+            Err(MkError::Interrupted { signal })
+        }
+        (None, _) => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::node::fixtures::{content, segment};
+
+    #[test]
+    fn should_plan_job_per_segment_with_prelude() {
+        let nodes = vec![
+            content("#!/bin/sh\n"),
+            segment("a", "a:\necho a\n", "", &[]),
+            segment("b", "b: a\necho b\n", "", &["a"]),
+            segment("c", "c: b\necho c\n", "", &["b"]),
+        ];
+        let segments = HashSet::from(["a", "b", "c"]);
+        assert_eq!(
+            plan(&nodes, &segments),
+            vec![
+                Job {
+                    name: "a",
+                    script: "#!/bin/sh\na:\necho a\n".to_string(),
+                    dependencies: vec![],
+                },
+                Job {
+                    name: "b",
+                    script: "#!/bin/sh\nb: a\necho b\n".to_string(),
+                    dependencies: vec![0],
+                },
+                Job {
+                    name: "c",
+                    script: "#!/bin/sh\nc: b\necho c\n".to_string(),
+                    dependencies: vec![1],
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn should_run_group_content_within_its_jobs() {
+        let nodes = vec![
+            segment("g", "g: x\npushd g\n", "", &["x"]),
+            segment("g/a", "g/a: g x\nmake a\n", "", &["g", "x"]),
+            segment("g/b", "g/b: g x\nmake b\n", "", &["g", "x"]),
+            segment("g", "g:\npopd\n", "", &[]),
+            segment("x", "x:\nmake x\n", "", &[]),
+        ];
+        let segments = HashSet::from(["g", "g/a", "g/b", "x"]);
+        let jobs = plan(&nodes, &segments);
+        let names: Vec<_> = jobs.iter().map(|j| j.name).collect();
+        assert_eq!(names, vec!["g/a", "g/b", "x"]);
+        assert_eq!(
+            jobs[0].script,
+            "g: x\npushd g\ng/a: g x\nmake a\ng:\npopd\n"
+        );
+        assert_eq!(jobs[0].dependencies, vec![2]);
+        assert_eq!(jobs[1].dependencies, vec![2]);
+    }
+
+    #[test]
+    fn should_depend_on_jobs_behind_group_dependency() {
+        let nodes = vec![
+            segment("g", "g: x\n", "", &["x"]),
+            segment("g/a", "g/a: g x\n", "", &["g", "x"]),
+            segment("x", "x:\n", "", &[]),
+            segment("y", "y: g\n", "", &["g"]),
+        ];
+        let segments = HashSet::from(["g", "g/a", "x", "y"]);
+        let jobs = plan(&nodes, &segments);
+        assert_eq!(jobs[2].name, "y");
+        assert_eq!(jobs[2].dependencies, vec![1]);
+    }
+
+    #[test]
+    fn should_return_failed_script_status() {
+        let options = Options {
+            shell: "sh".to_string(),
+            ..Options::default()
+        };
+        let error = run_script::<&str>("exit 3\n", &options, &[]).unwrap_err();
+        assert!(matches!(
+            &error,
+            MkError::ScriptFailed { segment: None, status } if status.code() == Some(3)
+        ));
+        assert_eq!(error.exit_code(), 3);
+    }
+
+    #[test]
+    fn should_forget_child_before_reaping_it() {
+        let children = Mutex::new(HashSet::new());
+        let mut child = std::process::Command::new("sh")
+            .args(["-c", "exit 4"])
+            .spawn()
+            .unwrap();
+        children.lock().unwrap().insert(child.id());
+        let status = SignalForwarder::wait(&children, &mut child).unwrap();
+        assert_eq!(status.code(), Some(4));
+        assert!(children.lock().unwrap().is_empty());
+    }
+}
@@ -0,0 +1,137 @@
+use std::collections::{HashMap, HashSet, VecDeque};
+
+use super::chars::contains_glob_pattern_symbols;
+use super::graph::{definitions, dependencies, is_parent_group};
+use super::node::{Dependency, Node};
+
+/// Name of the dependency in a chain, with the group or the glob pattern it comes from
+fn hop(segment: &str, dependency: &Dependency) -> String {
+    if is_parent_group(dependency.name, segment) {
+        return format!("{} (group)", dependency.name);
+    }
+    match dependency.pattern {
+        Some(pattern) => format!("{} ({pattern})", dependency.name),
+        None => dependency.name.to_string(),
+    }
+}
+
+/// Why each selected segment is selected: the shortest dependency chain
+/// from a requested target, followed by the expansion of the glob
+/// dependencies of the selected segments
+pub fn explain(nodes: &[Node], targets: &[&str], segments: &HashSet<&str>) -> String {
+    let selected: Vec<_> = definitions(nodes, segments)
+        .into_iter()
+        .map(|(name, nodes)| (name, dependencies(&nodes)))
+        .collect();
+    let dependencies: HashMap<_, _> = selected.iter().map(|(n, d)| (*n, d)).collect();
+    // chain of each segment reached from the targets
+    let mut chains: HashMap<&str, Vec<String>> = HashMap::new();
+    let mut queue = VecDeque::new();
+    for target in targets {
+        if !chains.contains_key(target) {
+            chains.insert(target, vec![target.to_string()]);
+            queue.push_back(*target);
+        }
+    }
+    while let Some(name) = queue.pop_front() {
+        for dep in dependencies.get(name).into_iter().flat_map(|d| d.iter()) {
+            if chains.contains_key(dep.name) || !dependencies.contains_key(dep.name) {
+                continue;
+            }
+            let mut chain = chains[name].clone();
+            chain.push(hop(name, dep));
+            chains.insert(dep.name, chain);
+            queue.push_back(dep.name);
+        }
+    }
+    let mut out = String::new();
+    for (name, _) in &selected {
+        match chains.get(name) {
+            Some(chain) if chain.len() == 1 => out.push_str(&format!("{name}: requested\n")),
+            Some(chain) => out.push_str(&format!("{name}: {}\n", chain.join(" -> "))),
+            None => out.push_str(&format!("{name}: selected\n")),
+        }
+    }
+    for (name, deps) in &selected {
+        let mut patterns: Vec<(&str, Vec<&str>)> = Vec::new();
+        for dep in deps {
+            let (pattern, expanded) = match dep.pattern {
+                Some(pattern) => (pattern, Some(dep.name)),
+                // a pattern matching no segment is kept as the dependency
+                None if contains_glob_pattern_symbols(dep.name) => (dep.name, None),
+                None => continue,
+            };
+            let i = match patterns.iter().position(|(p, _)| *p == pattern) {
+                Some(i) => i,
+                None => {
+                    patterns.push((pattern, Vec::new()));
+                    patterns.len() - 1
+                }
+            };
+            if let Some(expanded) = expanded
+                && !patterns[i].1.contains(&expanded)
+            {
+                patterns[i].1.push(expanded);
+            }
+        }
+        for (pattern, expanded) in patterns {
+            if expanded.is_empty() {
+                out.push_str(&format!("{name}: {pattern} matches no segment\n"));
+            } else {
+                out.push_str(&format!("{name}: {pattern} -> {}\n", expanded.join(" ")));
+            }
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::node::fixtures::segment;
+
+    /// Segment with the dependencies expanded from the pattern
+    fn expanded<'a>(mut node: Node<'a>, pattern: &'a str, names: &[&str]) -> Node<'a> {
+        if let Node::Segment { dependencies, .. } = &mut node {
+            for dependency in dependencies.iter_mut() {
+                if names.contains(&dependency.name) {
+                    dependency.pattern = Some(pattern);
+                }
+            }
+        }
+        node
+    }
+
+    #[test]
+    fn should_explain_chains_and_patterns() {
+        let nodes = &[
+            segment("p/a/build", "", "", &[]),
+            segment("p/b/build", "", "", &[]),
+            segment("app", "", "", &[]),
+            expanded(
+                segment(
+                    "app/deps",
+                    "",
+                    "",
+                    &["app", "p/a/build", "p/b/build", "c/*"],
+                ),
+                "p/*/build",
+                &["p/a/build", "p/b/build"],
+            ),
+            segment("app/build", "", "", &["app", "app/deps"]),
+            segment("unused", "", "", &[]),
+        ];
+        let segments = HashSet::from(["p/a/build", "p/b/build", "app", "app/deps", "app/build"]);
+        assert_eq!(
+            explain(nodes, &["app/build"], &segments),
+            "p/a/build: app/build -> app/deps -> p/a/build (p/*/build)
+p/b/build: app/build -> app/deps -> p/b/build (p/*/build)
+app: app/build -> app (group)
+app/deps: app/build -> app/deps
+app/build: requested
+app/deps: p/*/build -> p/a/build p/b/build
+app/deps: c/* matches no segment
+"
+        );
+    }
+}
@@ -0,0 +1,290 @@
+use std::collections::HashSet;
+
+use serde_json::{Value, json};
+
+use crate::graph::{self, is_parent_group};
+use crate::node::Node;
+use crate::source::Sources;
+
+/// Segment names with the group of their definition and their dependencies
+pub type Subgraph<'a> = [(&'a str, Option<&'a str>, Vec<&'a str>)];
+
+/// Groups the segments are defined in, in the order of their first segment
+fn groups<'a>(subgraph: &Subgraph<'a>) -> Vec<&'a str> {
+    let mut groups = Vec::new();
+    for (_, group, _) in subgraph {
+        if let Some(group) = group
+            && !groups.contains(group)
+        {
+            groups.push(*group);
+        }
+    }
+    groups
+}
+
+/// Innermost of the groups containing the group, empty for a top level group
+fn parent<'a>(group: &str, groups: &[&'a str]) -> &'a str {
+    groups
+        .iter()
+        .filter(|parent| is_parent_group(parent, group))
+        .max_by_key(|parent| parent.len())
+        .copied()
+        .unwrap_or("")
+}
+
+fn quote(name: &str) -> String {
+    format!("\"{}\"", name.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+enum Item<'a> {
+    Segment(&'a str),
+    Group(&'a str),
+    End,
+}
+
+/// Segments and nested groups of `parent` with their depth
+fn layout<'a>(
+    subgraph: &Subgraph<'a>,
+    groups: &[&'a str],
+    parent: &str,
+    depth: usize,
+    items: &mut Vec<(usize, Item<'a>)>,
+) {
+    for (name, ..) in subgraph
+        .iter()
+        .filter(|(_, group, _)| group.unwrap_or("") == parent)
+    {
+        items.push((depth, Item::Segment(name)));
+    }
+    for nested in groups.iter().filter(|g| self::parent(g, groups) == parent) {
+        items.push((depth, Item::Group(nested)));
+        layout(subgraph, groups, nested, depth + 1, items);
+        items.push((depth, Item::End));
+    }
+}
+
+/// Graphviz graph with a cluster per group
+pub fn dot(subgraph: &Subgraph) -> String {
+    let mut items = Vec::new();
+    layout(subgraph, &groups(subgraph), "", 1, &mut items);
+    let mut out = String::from("digraph mk {\n");
+    for (depth, item) in items {
+        let indent = "  ".repeat(depth);
+        match item {
+            Item::Segment(name) => out.push_str(&format!("{indent}{};\n", quote(name))),
+            Item::Group(group) => out.push_str(&format!(
+                "{indent}subgraph {} {{\n{indent}  label = {};\n",
+                quote(&format!("cluster_{group}")),
+                quote(&format!("{group}/"))
+            )),
+            Item::End => out.push_str(&format!("{indent}}}\n")),
+        }
+    }
+    for (name, _, dependencies) in subgraph {
+        for dep in dependencies {
+            out.push_str(&format!("  {} -> {};\n", quote(name), quote(dep)));
+        }
+    }
+    out.push_str("}\n");
+    out
+}
+
+/// Mermaid flowchart with a subgraph per group
+pub fn mermaid(subgraph: &Subgraph) -> String {
+    // names may contain characters not allowed in ids
+    let id = |name: &str| {
+        let i = subgraph.iter().position(|(n, ..)| *n == name).unwrap();
+        format!("s{i}")
+    };
+    let groups = groups(subgraph);
+    let mut items = Vec::new();
+    layout(subgraph, &groups, "", 1, &mut items);
+    let mut out = String::from("flowchart LR\n");
+    for (depth, item) in items {
+        let indent = "    ".repeat(depth);
+        match item {
+            Item::Segment(name) => {
+                out.push_str(&format!("{indent}{}[{}]\n", id(name), quote(name)))
+            }
+            Item::Group(group) => {
+                let i = groups.iter().position(|g| *g == group).unwrap();
+                out.push_str(&format!(
+                    "{indent}subgraph g{i} [{}]\n",
+                    quote(&format!("{group}/"))
+                ))
+            }
+            Item::End => out.push_str(&format!("{indent}end\n")),
+        }
+    }
+    for (name, _, dependencies) in subgraph {
+        for dep in dependencies {
+            out.push_str(&format!("    {} --> {}\n", id(name), id(dep)));
+        }
+    }
+    out
+}
+
+/// Adjacency lists of the segments with their groups
+pub fn json(subgraph: &Subgraph) -> String {
+    let segments: Vec<_> = subgraph
+        .iter()
+        .map(|(name, group, dependencies)| {
+            json!({
+                "name": name,
+                "group": group,
+                "dependencies": dependencies,
+            })
+        })
+        .collect();
+    let mut out = serde_json::to_string_pretty(&json!({ "segments": segments })).unwrap();
+    out.push('\n');
+    out
+}
+
+/// Selected segments in the order of the first definition
+/// with the dependencies of all definitions
+pub fn targets_json(nodes: &[Node], segments: &HashSet<&str>, sources: &Sources) -> String {
+    let mut targets = Vec::new();
+    for (name, nodes) in graph::definitions(nodes, segments) {
+        let Node::Segment { location, .. } = nodes[0] else {
+            unreachable!()
+        };
+        let mut deps: Vec<Value> = Vec::new();
+        for dep in graph::dependencies(&nodes) {
+            if deps.iter().all(|d| d["name"] != dep.name) {
+                deps.push(json!({ "name": dep.name, "pattern": dep.pattern }));
+            }
+        }
+        let description = nodes
+            .iter()
+            .filter_map(|node| node.description())
+            .find(|description| !description.is_empty())
+            .unwrap_or_default();
+        targets.push(json!({
+            "name": name,
+            "group": nodes[0].group(sources),
+            "dependencies": deps,
+            "description": description,
+            "file": location.file,
+            "line": location.line,
+        }));
+    }
+    let mut out = serde_json::to_string_pretty(&json!({ "targets": targets })).unwrap();
+    out.push('\n');
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn subgraph() -> Vec<(&'static str, Option<&'static str>, Vec<&'static str>)> {
+        vec![
+            ("g", None, vec!["x"]),
+            ("g/a", Some("g"), vec!["g", "x"]),
+            ("g/h/b", Some("g/h"), vec!["g/a"]),
+            ("p/a/build", Some("p"), vec![]),
+            ("x", None, vec![]),
+        ]
+    }
+
+    #[test]
+    fn should_export_dot_with_clusters() {
+        assert_eq!(
+            dot(&subgraph()),
+            r#"digraph mk {
+  "g";
+  "x";
+  subgraph "cluster_g" {
+    label = "g/";
+    "g/a";
+    subgraph "cluster_g/h" {
+      label = "g/h/";
+      "g/h/b";
+    }
+  }
+  subgraph "cluster_p" {
+    label = "p/";
+    "p/a/build";
+  }
+  "g" -> "x";
+  "g/a" -> "g";
+  "g/a" -> "x";
+  "g/h/b" -> "g/a";
+}
+"#
+        );
+    }
+
+    #[test]
+    fn should_export_mermaid_with_subgraphs() {
+        assert_eq!(
+            mermaid(&subgraph()),
+            r#"flowchart LR
+    s0["g"]
+    s4["x"]
+    subgraph g0 ["g/"]
+        s1["g/a"]
+        subgraph g1 ["g/h/"]
+            s2["g/h/b"]
+        end
+    end
+    subgraph g2 ["p/"]
+        s3["p/a/build"]
+    end
+    s0 --> s4
+    s1 --> s0
+    s1 --> s4
+    s2 --> s1
+"#
+        );
+    }
+
+    #[test]
+    fn should_export_json_adjacency() {
+        let value: serde_json::Value = serde_json::from_str(&json(&subgraph())).unwrap();
+        assert_eq!(
+            value["segments"][2],
+            json!({"name": "g/h/b", "group": "g/h", "dependencies": ["g/a"]})
+        );
+        assert_eq!(value["segments"][3]["group"], "p");
+        assert_eq!(value["segments"][0]["group"], serde_json::Value::Null);
+    }
+
+    #[test]
+    fn should_list_targets_with_sources() {
+        use crate::syntax::{self, fixtures};
+
+        let sources =
+            fixtures::sources("g/:\n    a:\n        # builds a\n        make a\n\nb: g/*\n");
+        let content = fixtures::desugar(&sources);
+        let nodes = syntax::nodes(&content, &sources);
+        let value: Value = serde_json::from_str(&targets_json(
+            &nodes,
+            &HashSet::from(["g/a", "b"]),
+            &sources,
+        ))
+        .unwrap();
+        assert_eq!(
+            value["targets"],
+            json!([
+                {
+                    "name": "g/a",
+                    "group": "g",
+                    "dependencies": [{"name": "g", "pattern": null}],
+                    "description": [" builds a"],
+                    "file": "mkfile",
+                    "line": 2,
+                },
+                {
+                    "name": "b",
+                    "group": null,
+                    "dependencies": [{"name": "g/a", "pattern": "g/*"}],
+                    "description": [],
+                    "file": "mkfile",
+                    "line": 6,
+                },
+            ])
+        );
+    }
+}
@@ -0,0 +1,259 @@
+use std::collections::BTreeMap;
+use std::fs;
+
+use super::error::MkError;
+use super::graph::{self, Order};
+use super::include;
+use super::node::Node;
+use super::parameters;
+use super::segments_scanner::SegmentsScanner;
+use super::source::{SourceFile, Sources};
+use super::syntax;
+use super::variables;
+
+// Indentation of a segment or a group content per nesting level
+const INDENTATION: &str = "    ";
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Style {
+    pub sort_dependencies: bool,
+}
+
+/// Name and dependencies of a header line starting with a segment or group name
+fn header(line: &str) -> Option<(&str, Vec<&str>)> {
+    if variables::definition(line).is_some() {
+        return None;
+    }
+    let colon = line.find(':')?;
+    let name = &line[..colon];
+    if !parameters::is_segment_name(name) {
+        return None;
+    }
+    Some((name, line[colon + 1..].split_whitespace().collect()))
+}
+
+fn leading_whitespace(line: &str) -> &str {
+    &line[..line.len() - line.trim_start().len()]
+}
+
+/// Lines of a nesting level with the indentation of the level removed.
+/// Group and segment contents are delimited the way `groups::desugar`
+/// and `SegmentsScanner` do it: by the indentation of their first line.
+fn format_level(lines: &[&str], depth: usize, style: &Style, out: &mut Vec<String>) {
+    let prefix = INDENTATION.repeat(depth);
+    let mut i = 0;
+    while i < lines.len() {
+        let line = lines[i];
+        i += 1;
+        let Some((name, mut dependencies)) = header(line) else {
+            out.push(format!("{prefix}{line}"));
+            continue;
+        };
+        if style.sort_dependencies {
+            dependencies.sort();
+        }
+        let mut formatted = format!("{prefix}{name}:");
+        for dep in dependencies {
+            formatted.push(' ');
+            formatted.push_str(dep);
+        }
+        out.push(formatted);
+        let indentation = lines.get(i).map(|l| leading_whitespace(l)).unwrap_or("");
+        if indentation.is_empty() {
+            continue;
+        }
+        let body: Vec<&str> = lines[i..]
+            .iter()
+            .take_while(|l| l.starts_with(indentation))
+            .map(|l| &l[indentation.len()..])
+            .collect();
+        i += body.len();
+        if name.ends_with('/') && name.len() > 1 {
+            format_level(&body, depth + 1, style, out);
+        } else {
+            // tabs nested in a body are a part of its script, so it keeps its unit
+            let unit = match body.iter().any(|line| line.starts_with('\t')) {
+                true => indentation,
+                false => INDENTATION,
+            };
+            for line in body {
+                out.push(format!("{prefix}{unit}{line}"));
+            }
+        }
+    }
+}
+
+/// Content in the canonical form: one space between the header parts
+/// and the same indentation per nesting level, unless a segment body nests
+/// tabs; other text is kept as is
+pub fn format(content: &str, style: &Style) -> String {
+    let lines: Vec<&str> = content.split('\n').collect();
+    let mut out = Vec::with_capacity(lines.len());
+    format_level(&lines, 0, style, &mut out);
+    out.join("\n")
+}
+
+/// Output of every segment as a target and of the common content alone
+fn outputs(sources: &Sources) -> Result<BTreeMap<String, String>, String> {
+    let content = syntax::desugar(sources, &Default::default()).map_err(|e| e.to_string())?;
+    let nodes: Vec<_> = SegmentsScanner::new(&content.content).collect();
+    let mut outputs = BTreeMap::new();
+    let options = graph::Options::default();
+    // the common content is keyed by the empty name
+    outputs.insert(
+        String::new(),
+        graph::render(&nodes, &Default::default(), Order::File),
+    );
+    for node in &nodes {
+        let Node::Segment { name, .. } = node else {
+            continue;
+        };
+        let output = match graph::resolve_segments(&nodes, &[name], &options) {
+            Ok(resolved) => graph::render(&nodes, &resolved.segments, Order::File),
+            Err(error) => error.to_string(),
+        };
+        outputs.insert(name.to_string(), output);
+    }
+    Ok(outputs)
+}
+
+fn single(name: &str, content: &str) -> Sources {
+    let mut sources = Sources::new(vec![SourceFile {
+        name: name.to_string(),
+        content: content.to_string(),
+    }]);
+    include::read(&mut sources, |name| fs::read_to_string(name));
+    sources
+}
+
+/// Formatted content of the file, unless it would change the output of a target
+pub fn format_file<'a>(file: &SourceFile, style: &Style) -> Result<String, MkError<'a>> {
+    let formatted = format(&file.content, style);
+    let before = outputs(&single(&file.name, &file.content));
+    let after = outputs(&single(&file.name, &formatted));
+    match (before, after) {
+        (Ok(before), Ok(after)) => {
+            let changed = before
+                .iter()
+                .find(|(target, output)| after.get(*target) != Some(output))
+                .map(|(target, _)| target)
+                .or_else(|| after.keys().find(|t| !before.contains_key(*t)));
+            match changed {
+                None => Ok(formatted),
+                Some(target) => Err(MkError::FormatChangesOutput {
+                    file: file.name.clone(),
+                    target: Some(target.clone()).filter(|t| !t.is_empty()),
+                }),
+            }
+        }
+        // an invalid file is left for the other commands to report
+        (Err(_), _) => Ok(file.content.clone()),
+        (Ok(_), Err(_)) => Err(MkError::FormatChangesOutput {
+            file: file.name.clone(),
+            target: None,
+        }),
+    }
+}
+
+/// Formats the files in place or, for the standard input, to the standard output.
+/// With `check` nothing is written and the files that would change are reported.
+pub fn run<'a>(
+    sources: &Sources,
+    style: &Style,
+    check: bool,
+    in_place: bool,
+) -> Result<(), MkError<'a>> {
+    let mut unformatted = Vec::new();
+    // an included file is formatted when it is given as an input itself
+    for file in &sources.files()[..sources.inputs()] {
+        let formatted = format_file(file, style)?;
+        if check {
+            if formatted != file.content {
+                unformatted.push(file.name.clone());
+            }
+        } else if !in_place {
+            print!("{formatted}");
+        } else if formatted != file.content {
+            fs::write(&file.name, formatted).map_err(|source| MkError::Io {
+                path: Some(file.name.clone()),
+                source,
+            })?;
+        }
+    }
+    if unformatted.is_empty() {
+        Ok(())
+    } else {
+        Err(MkError::Unformatted { files: unformatted })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn file(content: &str) -> SourceFile {
+        SourceFile {
+            name: "mkfile".to_string(),
+            content: content.to_string(),
+        }
+    }
+
+    #[test]
+    fn should_normalize_headers() {
+        assert_eq!(
+            format(
+                "# comment\nfoo:bar   baz \n\techo\n\nbar :\n",
+                &Style::default()
+            ),
+            "# comment\nfoo: bar baz\n    echo\n\nbar :\n"
+        );
+    }
+
+    #[test]
+    fn should_indent_per_group_depth() {
+        assert_eq!(
+            format(
+                "g/:\n  pushd g\n  h/: /x\n  \tfoo:\n  \t  echo foo\n  popd\n",
+                &Style::default()
+            ),
+            "g/:\n    pushd g\n    h/: /x\n        foo:\n            echo foo\n    popd\n"
+        );
+    }
+
+    #[test]
+    fn should_sort_dependencies() {
+        let style = Style {
+            sort_dependencies: true,
+        };
+        assert_eq!(format("foo: c a b\n", &style), "foo: a b c\n");
+    }
+
+    #[test]
+    fn should_leave_invalid_file() {
+        let content = "g/:\n  a:\n\techo a\n";
+        assert_eq!(
+            format_file(&file(content), &Style::default()).unwrap(),
+            content
+        );
+    }
+
+    #[test]
+    fn should_keep_output_of_targets() {
+        let content = "#!/bin/sh\ng/:\n  a:\n  \techo a\n  \n  b:  a\n  \t\techo b\n\nc: g/b\n\tif true; then\n\t\techo c\n\tfi\n";
+        let formatted = format_file(&file(content), &Style::default()).unwrap();
+        assert_eq!(
+            formatted,
+            "#!/bin/sh\ng/:\n    a:\n        echo a\n    \n    b: a\n        echo b\n\nc: g/b\n\tif true; then\n\t\techo c\n\tfi\n"
+        );
+    }
+
+    #[test]
+    fn should_format_only_inputs() {
+        let mut sources = Sources::new(vec![file("x:\n    echo x\n")]);
+        sources.include(SourceFile {
+            name: "m2".to_string(),
+            content: "y:z\n\techo y\n".to_string(),
+        });
+        assert!(run(&sources, &Style::default(), true, false).is_ok());
+    }
+}
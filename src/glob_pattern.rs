@@ -1,31 +1,35 @@
 use std::collections::HashSet;
-use std::iter;
 
 use glob::Pattern;
 
 use super::chars::*;
 use super::node::Node;
 use super::segments_scanner::SegmentsScanner;
+use super::source::{MappedText, Origin, line_column, line_starts, lines_count, span_map};
 
-fn resolve_glob_pattern<'a>(segments: &[&'a str], pattern_str: &'a str) -> HashSet<&'a str> {
-    let mut result = HashSet::new();
-    match Pattern::new(pattern_str) {
-        Ok(pattern) => {
-            for segment in segments {
-                if pattern.matches(segment) {
-                    result.insert(*segment);
-                }
-            }
-        }
-        Err(_) => {
-            result.insert(pattern_str);
-        }
-    }
-    result
+#[derive(Debug, PartialEq)]
+pub struct PatternError {
+    pub pattern: String,
+    pub reason: String,
+    pub offset: usize,
 }
 
-pub fn desugar(content: &str) -> String {
-    let nodes: Vec<_> = SegmentsScanner::new(content).collect();
+fn resolve_glob_pattern<'a>(
+    segments: &[&'a str],
+    pattern_str: &str,
+) -> Result<HashSet<&'a str>, glob::PatternError> {
+    let pattern = Pattern::new(pattern_str)?;
+    Ok(segments
+        .iter()
+        .filter(|segment| pattern.matches(segment))
+        .copied()
+        .collect())
+}
+
+/// Glob expansion keeps the lines of the content in place,
+/// each expanded dependency originates from its pattern and records it
+pub fn desugar(text: &MappedText) -> Result<MappedText, PatternError> {
+    let nodes: Vec<_> = SegmentsScanner::new(&text.content).collect();
     let segments: Vec<_> = nodes
         .iter()
         .filter_map(|n| match n {
@@ -33,50 +37,128 @@ pub fn desugar(content: &str) -> String {
             _ => None,
         })
         .collect();
-    nodes
-        .into_iter()
-        .map(|n| match n {
-            Node::Content(c) => c.to_string(),
+    let starts = line_starts(&text.content);
+    let origin = |offset: usize| {
+        let (line, column) = line_column(&starts, offset);
+        text.map.locate(Origin { line, column })
+    };
+    let mut result = MappedText::default();
+    for node in &nodes {
+        match node {
+            Node::Content { content, span, .. } => {
+                result.push(content, &span_map(&starts, &text.map, *span))
+            }
             Node::Segment {
                 name,
                 content,
                 dependencies,
+                span,
                 ..
             } => {
-                format!(
-                    "{}:{}\n{}",
-                    name,
-                    iter::once("")
-                        .chain(dependencies.into_iter().flat_map(|d| {
-                            if !contains_glob_pattern_symbols(d) {
-                                return vec![d].into_iter();
-                            }
-                            let mut segments = resolve_glob_pattern(&segments, d)
-                                .into_iter()
-                                // TODO: figure out how use HashSet into_iter directly
-                                .collect::<Vec<_>>();
-                            segments.sort();
-                            segments.into_iter()
-                        }))
-                        .collect::<Vec<_>>()
-                        .join(" "),
-                    content
-                )
+                let mut names = Vec::new();
+                let mut exclusions = Vec::new();
+                for d in dependencies {
+                    if let Some(excluded) = d.name.strip_prefix('!') {
+                        exclusions.push((excluded, d.span.start + 1));
+                        continue;
+                    }
+                    if !contains_glob_pattern_symbols(d.name) {
+                        names.push((d.name, origin(d.span.start), None));
+                        continue;
+                    }
+                    let mut matches: Vec<_> = resolve_glob_pattern(&segments, d.name)
+                        .map_err(|e| PatternError {
+                            pattern: d.name.to_string(),
+                            reason: e.msg.to_string(),
+                            offset: d.span.start,
+                        })?
+                        .into_iter()
+                        .collect();
+                    // Unmatched pattern is left to be reported on resolution
+                    if matches.is_empty() {
+                        names.push((d.name, origin(d.span.start), None));
+                    }
+                    matches.sort();
+                    names.extend(
+                        matches
+                            .into_iter()
+                            .map(|name| (name, origin(d.span.start), Some(d.name))),
+                    );
+                }
+                // exclusions apply to the whole list wherever they are written
+                for (excluded, offset) in exclusions {
+                    let pattern = Pattern::new(excluded).map_err(|e| PatternError {
+                        pattern: excluded.to_string(),
+                        reason: e.msg.to_string(),
+                        offset,
+                    })?;
+                    names.retain(|(name, ..)| !pattern.matches(name));
+                }
+                let map = span_map(&starts, &text.map, *span);
+                result.push_header(name, &names, origin(span.start));
+                result.push_lines("\n", map.get(0));
+                result.push(content, &map.slice(1, lines_count(content)));
             }
-        })
-        .collect::<Vec<_>>()
-        .join("")
+        }
+    }
+    Ok(result)
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    fn desugar_text(content: &str) -> Result<String, PatternError> {
+        desugar(&MappedText::new(content)).map(|text| text.content)
+    }
+
     #[test]
     fn should_desugar_content() {
         assert_eq!(
-            desugar("f/check:\nf/build:\nbuild: f/*"),
-            "f/check:\nf/build:\nbuild: f/build f/check\n"
+            desugar_text("f/check:\nf/build:\nbuild: f/*"),
+            Ok("f/check:\nf/build:\nbuild: f/build f/check\n".to_string())
+        );
+    }
+
+    #[test]
+    fn should_remove_excluded_dependencies() {
+        assert_eq!(
+            desugar_text("f/check:\nf/build:\ng/build:\nbuild: !f/* */build lib"),
+            Ok("f/check:\nf/build:\ng/build:\nbuild: g/build lib\n".to_string())
+        );
+    }
+
+    #[test]
+    fn should_keep_unmatched_pattern() {
+        assert_eq!(
+            desugar_text("f/check:\nbuild: f/*/build"),
+            Ok("f/check:\nbuild: f/*/build\n".to_string())
+        );
+    }
+
+    #[test]
+    fn should_reject_bad_pattern() {
+        assert_eq!(
+            desugar_text("f/check:\nbuild: f/[a"),
+            Err(PatternError {
+                pattern: "f/[a".to_string(),
+                reason: "invalid range pattern".to_string(),
+                offset: 16,
+            })
+        );
+    }
+
+    #[test]
+    fn should_record_pattern_of_expanded_dependency() {
+        let text = desugar(&MappedText::new("f/check:\nf/build:\nbuild:  lib   f/*")).unwrap();
+        assert_eq!(
+            text.content,
+            "f/check:\nf/build:\nbuild: lib f/build f/check\n"
         );
+        let at = |column| Origin { line: 2, column };
+        assert_eq!(text.map.locate(at(7)), at(8));
+        assert_eq!(text.map.pattern(at(7)), None);
+        assert_eq!(text.map.locate(at(19)), at(14));
+        assert_eq!(text.map.pattern(at(19)), Some("f/*"));
     }
 }
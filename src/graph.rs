@@ -1,199 +1,880 @@
-use std::collections::{HashMap, HashSet};
+use std::collections::{HashMap, HashSet, VecDeque};
 
+use clap::ValueEnum;
 use glob::Pattern;
 
 use super::chars::*;
-use super::node::Node;
+use super::error::MkError;
+use super::node::{Dependency, Node};
+use super::source::Sources;
+use super::suggestions;
 
-fn make_graph<'a>(nodes: &[Node<'a>]) -> HashMap<&'a str, HashSet<&'a str>> {
-    let mut graph: HashMap<&'a str, HashSet<&'a str>> = HashMap::new();
+#[derive(Debug, Clone, Copy, Default, PartialEq, ValueEnum)]
+pub enum Order {
+    /// Segments are emitted in the order of definition
+    #[default]
+    File,
+    /// Segments are emitted after their dependencies
+    Dependencies,
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Options {
+    /// Fail on dependencies that name no segment instead of warning
+    pub strict: bool,
+    pub order: Order,
+    /// Accept an unambiguous abbreviation of a target, see [`abbreviated`]
+    pub abbreviations: bool,
+    /// Levels of dependencies selected below the targets, all if unset
+    pub depth: Option<usize>,
+    /// Select the dependencies of the targets without the targets
+    pub only_dependencies: bool,
+    /// Report why each segment is selected
+    pub explain: bool,
+}
+
+// Segment run without a target by the legacy version
+const DEFAULT_SEGMENT: &str = "default";
+
+pub struct Resolved<'a> {
+    /// Segments the targets and patterns selected
+    pub targets: Vec<&'a str>,
+    pub segments: HashSet<&'a str>,
+    pub warnings: Vec<MkError<'a>>,
+}
+
+/// Dependencies of each segment from all of its definitions
+type Graph<'a> = HashMap<&'a str, Vec<Dependency<'a>>>;
+
+fn make_graph<'a>(nodes: &[Node<'a>]) -> Graph<'a> {
+    let mut graph: Graph<'a> = HashMap::new();
     for node in nodes {
         match node {
-            Node::Content(_) => {}
+            Node::Content { .. } => {}
             Node::Segment {
                 name, dependencies, ..
             } => {
-                graph.insert(name, dependencies.iter().cloned().collect());
+                graph
+                    .entry(*name)
+                    .or_default()
+                    .extend(dependencies.iter().cloned());
             }
         }
     }
     graph
 }
 
-fn resolve_targets<'a>(
-    graph: &HashMap<&'a str, HashSet<&'a str>>,
+/// Cycle of the depth-first path, each segment on it
+/// is left by the dependency before its next one to visit
+fn dependency_cycle<'a>(
+    graph: &Graph<'a>,
+    cycle: &[(&'a str, usize)],
+    last: &'a str,
+) -> MkError<'a> {
+    MkError::DependencyCycle {
+        path: cycle
+            .iter()
+            .map(|(name, _)| name.to_string())
+            .chain(std::iter::once(last.to_string()))
+            .collect(),
+        locations: cycle
+            .iter()
+            .filter_map(|(name, next)| Some(graph.get(name)?.get(next.checked_sub(1)?)?.location))
+            .collect(),
+    }
+}
+
+/// Groups add their name to dependencies of nested segments
+/// even if the group has no own content
+pub fn is_parent_group(dependency: &str, segment: &str) -> bool {
+    segment
+        .strip_prefix(dependency)
+        .is_some_and(|rest| rest.starts_with('/'))
+}
+
+/// Targets of a run that names none: the segments marked as default,
+/// else the configured target, else the segment named `default`
+pub fn default_targets<'a>(nodes: &[Node<'a>], configured: Option<&'a str>) -> Vec<&'a str> {
+    let mut marked = Vec::new();
+    for node in nodes {
+        if let Node::Segment { name, .. } = node
+            && node.is_default()
+            && !marked.contains(name)
+        {
+            marked.push(*name);
+        }
+    }
+    if !marked.is_empty() {
+        return marked;
+    }
+    if let Some(configured) = configured {
+        return vec![configured];
+    }
+    nodes
+        .iter()
+        .find_map(|node| match node {
+            Node::Segment { name, .. } if *name == DEFAULT_SEGMENT => Some(*name),
+            _ => None,
+        })
+        .into_iter()
+        .collect()
+}
+
+/// Visits the segments reached from the targets depth-first, passing the undefined
+/// dependencies and the dependency cycles to `report`, which stops on an error
+fn traverse<'a>(
+    graph: &Graph<'a>,
     targets: &[&'a str],
-) -> HashSet<&'a str> {
+    mut report: impl FnMut(MkError<'a>) -> Result<(), MkError<'a>>,
+) -> Result<HashSet<&'a str>, MkError<'a>> {
     let mut visited = HashSet::new();
+    // Depth-first path with the index of the next dependency to visit
+    let mut path: Vec<(&'a str, usize)> = Vec::new();
+    for target in targets {
+        if !visited.insert(*target) {
+            continue;
+        }
+        path.push((target, 0));
+        while let Some((node, next)) = path.last_mut() {
+            let deps = graph.get(node).map(Vec::as_slice).unwrap_or_default();
+            let Some(dep) = deps.get(*next) else {
+                path.pop();
+                continue;
+            };
+            *next += 1;
+            let node = *node;
+            if !graph.contains_key(dep.name) && !is_parent_group(dep.name, node) {
+                report(MkError::UndefinedDependency {
+                    segment: node.to_string(),
+                    dependency: dep.name.to_string(),
+                    location: dep.location,
+                })?;
+            }
+            if let Some(i) = path.iter().position(|(name, _)| *name == dep.name) {
+                report(dependency_cycle(graph, &path[i..], dep.name))?;
+                continue;
+            }
+            if visited.insert(dep.name) {
+                path.push((dep.name, 0));
+            }
+        }
+    }
+    Ok(visited)
+}
 
-    let mut stack = Vec::new();
-    stack.extend_from_slice(targets);
+fn resolve_targets<'a>(
+    graph: &Graph<'a>,
+    targets: &[&'a str],
+    options: &Options,
+) -> Result<Resolved<'a>, MkError<'a>> {
+    let mut warnings = Vec::new();
+    let mut visited = traverse(graph, targets, |error| match error {
+        MkError::UndefinedDependency { .. } if !options.strict => {
+            warnings.push(error);
+            Ok(())
+        }
+        error => Err(error),
+    })?;
+    if options.depth.is_some() || options.only_dependencies {
+        visited = limit(graph, targets, options);
+    }
+    Ok(Resolved {
+        targets: targets.to_vec(),
+        segments: visited,
+        warnings,
+    })
+}
 
-    while let Some(node) = stack.pop() {
-        if visited.contains(&node) {
+/// Segments within the depth of the targets, reached through a dependency
+/// if only the dependencies are selected. The parent groups of a segment
+/// are a part of its script, so they are on its level.
+fn limit<'a>(graph: &Graph<'a>, targets: &[&'a str], options: &Options) -> HashSet<&'a str> {
+    // lowest level of a segment as a target and as a dependency
+    let mut levels: HashMap<(&str, bool), usize> = HashMap::new();
+    let mut queue: VecDeque<_> = targets.iter().map(|t| (*t, false, 0)).collect();
+    while let Some((name, dependency, level)) = queue.pop_front() {
+        if levels
+            .get(&(name, dependency))
+            .is_some_and(|lowest| *lowest <= level)
+        {
             continue;
         }
-        visited.insert(node);
-        if let Some(deps) = graph.get(node) {
-            stack.extend(deps.iter());
+        levels.insert((name, dependency), level);
+        let Some(dependencies) = graph.get(name) else {
+            continue;
+        };
+        for dep in dependencies {
+            let (dependency, level) = if is_parent_group(dep.name, name) {
+                (dependency, level)
+            } else {
+                (true, level + 1)
+            };
+            if options.depth.is_none_or(|depth| level <= depth) {
+                queue.push_back((dep.name, dependency, level));
+            }
+        }
+    }
+    levels
+        .into_keys()
+        .filter(|(_, dependency)| *dependency || !options.only_dependencies)
+        .map(|(name, _)| name)
+        .collect()
+}
+
+/// Segment name abbreviated by the target: each `/`-separated component
+/// of the target is a prefix of the same component of the name,
+/// e.g. `a/p/b` for `app/ppp/build` or `bu` for `build`
+fn abbreviated<'a>(graph: &Graph<'a>, target: &str) -> Result<Option<&'a str>, MkError<'a>> {
+    let components: Vec<_> = target.split('/').collect();
+    let mut candidates: Vec<&str> = graph
+        .keys()
+        .copied()
+        .filter(|name| {
+            let mut name = name.split('/');
+            components
+                .iter()
+                .all(|c| name.next().is_some_and(|n| n.starts_with(c)))
+                && name.next().is_none()
+        })
+        .collect();
+    match candidates.len() {
+        0 => Ok(None),
+        1 => Ok(candidates.pop()),
+        _ => {
+            candidates.sort();
+            Err(MkError::AmbiguousTarget {
+                target: target.to_string(),
+                candidates: candidates.into_iter().map(str::to_string).collect(),
+            })
         }
     }
-    visited
 }
 
 pub fn resolve_segments<'a>(
     nodes: &[Node<'a>],
     targets_or_patterns: &[&'a str],
-) -> Result<HashSet<&'a str>, &'a str> {
+    options: &Options,
+) -> Result<Resolved<'a>, MkError<'a>> {
     let graph = make_graph(nodes);
     let mut targets = Vec::new();
+    let mut exclusions = Vec::new();
     for target in targets_or_patterns {
+        if let Some(excluded) = target.strip_prefix('!') {
+            exclusions.push(Pattern::new(excluded).map_err(|e| MkError::BadGlob {
+                pattern: excluded.to_string(),
+                reason: e.msg.to_string(),
+                location: None,
+            })?);
+            continue;
+        }
         if graph.contains_key(target) {
             targets.push(*target);
             continue;
         }
         let old_size = targets.len();
-        if contains_glob_pattern_symbols(*target) {
-            let pattern = Pattern::new(*target).map_err(|_| *target)?;
+        if contains_glob_pattern_symbols(target) {
+            let pattern = Pattern::new(target).map_err(|e| MkError::BadGlob {
+                pattern: target.to_string(),
+                reason: e.msg.to_string(),
+                location: None,
+            })?;
             for name in graph.keys() {
                 if pattern.matches(name) {
                     targets.push(name);
                 }
             }
         }
+        if targets.len() == old_size
+            && options.abbreviations
+            && let Some(name) = abbreviated(&graph, target)?
+        {
+            targets.push(name);
+            continue;
+        }
         if targets.len() == old_size {
-            return Err(*target);
+            return Err(MkError::UnknownTarget {
+                target: target.to_string(),
+                suggestions: suggestions::similar(target, graph.keys().copied())
+                    .into_iter()
+                    .map(str::to_string)
+                    .collect(),
+                group: suggestions::is_group_prefix(target, graph.keys().copied()),
+            });
         }
     }
-    Ok(resolve_targets(&graph, &targets))
+    // excluded targets still run as dependencies of the selected ones
+    targets.retain(|target| !exclusions.iter().any(|e| e.matches(target)));
+    resolve_targets(&graph, &targets, options)
+}
+
+/// Undefined dependencies and dependency cycles of all segments,
+/// each cycle reported from the edge closing it
+pub fn problems<'a>(nodes: &[Node<'a>]) -> Vec<MkError<'a>> {
+    let graph = make_graph(nodes);
+    let names: Vec<_> = nodes
+        .iter()
+        .filter_map(|node| match node {
+            Node::Segment { name, .. } => Some(*name),
+            Node::Content { .. } => None,
+        })
+        .collect();
+    let mut problems = Vec::new();
+    // the reports never stop the traversal
+    let _ = traverse(&graph, &names, |error| {
+        problems.push(error);
+        Ok(())
+    });
+    problems
 }
 
-pub fn resolve<'a>(nodes: &[Node<'a>], targets_or_patterns: &[&'a str]) -> Result<String, &'a str> {
-    let segments = resolve_segments(nodes, targets_or_patterns)?;
-    let mut blocks = Vec::new();
+/// Selected segments in the order of the first definition with all of their definitions
+pub fn definitions<'n, 'a>(
+    nodes: &'n [Node<'a>],
+    segments: &HashSet<&'a str>,
+) -> Vec<(&'a str, Vec<&'n Node<'a>>)> {
+    let mut definitions: Vec<(&str, Vec<&Node>)> = Vec::new();
     for node in nodes {
+        let Node::Segment { name, .. } = node else {
+            continue;
+        };
+        if !segments.contains(name) {
+            continue;
+        }
+        match definitions.iter_mut().find(|(n, _)| n == name) {
+            Some((_, nodes)) => nodes.push(node),
+            None => definitions.push((name, vec![node])),
+        }
+    }
+    definitions
+}
+
+/// Dependencies of all definitions of a segment
+pub fn dependencies<'n, 'a>(definitions: &[&'n Node<'a>]) -> Vec<&'n Dependency<'a>> {
+    definitions
+        .iter()
+        .flat_map(|node| match node {
+            Node::Segment { dependencies, .. } => dependencies.as_slice(),
+            Node::Content { .. } => &[],
+        })
+        .collect()
+}
+
+/// Selected segments in the order of the first definition, with the group
+/// it was defined in and the dependencies of all definitions that are selected segments
+pub fn subgraph<'a>(
+    nodes: &[Node<'a>],
+    segments: &HashSet<&'a str>,
+    sources: &Sources,
+) -> Vec<(&'a str, Option<&'a str>, Vec<&'a str>)> {
+    let definitions = definitions(nodes, segments);
+    let defined: HashSet<_> = definitions.iter().map(|(name, _)| *name).collect();
+    definitions
+        .iter()
+        .map(|(name, nodes)| {
+            let mut deps = Vec::new();
+            for dep in dependencies(nodes) {
+                if defined.contains(dep.name) && !deps.contains(&dep.name) {
+                    deps.push(dep.name);
+                }
+            }
+            (*name, nodes[0].group(sources), deps)
+        })
+        .collect()
+}
+
+/// Selected nodes in the file order
+fn file_order<'n, 'a>(nodes: &'n [Node<'a>], segments: &HashSet<&'a str>) -> Vec<&'n Node<'a>> {
+    nodes
+        .iter()
+        .filter(|node| match node {
+            Node::Content { .. } => true,
+            Node::Segment { name, .. } => segments.contains(name),
+        })
+        .collect()
+}
+
+/// Name of a segment node
+fn segment_name<'a>(node: &Node<'a>) -> Option<&'a str> {
+    match node {
+        Node::Segment { name, .. } => Some(*name),
+        _ => None,
+    }
+}
+
+/// Nodes of a group, or of the file, with each nested group block postponed
+/// as a whole until the dependencies of its segments outside of the block
+/// have been emitted, dependencies left out of the selection are not waited for.
+/// The definitions of the group itself and common content stay in place.
+fn order_group<'n, 'a>(
+    nodes: &[&'n Node<'a>],
+    group: Option<&'a str>,
+    segments: &HashSet<&'a str>,
+    emitted: &mut HashSet<&'a str>,
+    ordered: &mut Vec<&'n Node<'a>>,
+) {
+    let is_ready = |block: &[&Node<'a>], emitted: &HashSet<&'a str>| {
+        block.iter().all(|node| match node {
+            Node::Segment { dependencies, .. } => dependencies.iter().all(|d| {
+                emitted.contains(d.name)
+                    || !segments.contains(d.name)
+                    || block.iter().any(|n| segment_name(n) == Some(d.name))
+            }),
+            _ => true,
+        })
+    };
+    // definitions of the same segment keep their relative order
+    let shares_name = |block: &[&Node<'a>], other: &[&Node<'a>]| {
+        block
+            .iter()
+            .filter_map(|n| segment_name(n))
+            .any(|name| other.iter().any(|o| segment_name(o) == Some(name)))
+    };
+    let mut emit = |block: &[&'n Node<'a>], emitted: &mut HashSet<&'a str>| match block {
+        [node] => {
+            emitted.extend(segment_name(node));
+            ordered.push(node);
+        }
+        _ => order_group(block, segment_name(block[0]), segments, emitted, ordered),
+    };
+    let mut pending: Vec<&[&'n Node<'a>]> = Vec::new();
+    let mut i = 0;
+    while i < nodes.len() {
+        let name = match segment_name(nodes[i]) {
+            Some(name) if Some(name) != group => name,
+            _ => {
+                emit(&nodes[i..=i], emitted);
+                i += 1;
+                continue;
+            }
+        };
+        // a group definition is followed by its members and its later definitions
+        let len = 1 + nodes[i + 1..]
+            .iter()
+            .take_while(|n| segment_name(n).is_some_and(|n| n == name || is_parent_group(name, n)))
+            .count();
+        let block = &nodes[i..i + len];
+        i += len;
+        if pending.iter().any(|p| shares_name(p, block)) || !is_ready(block, emitted) {
+            pending.push(block);
+            continue;
+        }
+        emit(block, emitted);
+        while let Some(j) = (0..pending.len()).find(|&j| {
+            is_ready(pending[j], emitted)
+                && !pending[..j].iter().any(|p| shares_name(p, pending[j]))
+        }) {
+            let block = pending.remove(j);
+            emit(block, emitted);
+        }
+    }
+    for block in pending {
+        emit(block, emitted);
+    }
+}
+
+/// Selected nodes in the file order, except that segments are postponed
+/// until each of their dependencies has been emitted at least once.
+/// A group is postponed with its members so that they stay between its definitions.
+/// Common content stays in place.
+fn dependencies_order<'n, 'a>(
+    nodes: &'n [Node<'a>],
+    segments: &HashSet<&'a str>,
+) -> Vec<&'n Node<'a>> {
+    let mut ordered = Vec::new();
+    order_group(
+        &file_order(nodes, segments),
+        None,
+        segments,
+        &mut HashSet::new(),
+        &mut ordered,
+    );
+    ordered
+}
+
+/// Heredoc delimiter that does not occur in the content
+fn heredoc_delimiter(content: &str) -> String {
+    let mut delimiter = "MK_SEGMENT".to_string();
+    while content.lines().any(|line| line.trim() == delimiter) {
+        delimiter.push('_');
+    }
+    delimiter
+}
+
+/// Content of the nodes with only the selected segments.
+/// Segments annotated with an interpreter are passed to it as a heredoc.
+pub fn render<'a>(nodes: &[Node<'a>], segments: &HashSet<&'a str>, order: Order) -> String {
+    let nodes = match order {
+        Order::File => file_order(nodes, segments),
+        Order::Dependencies => dependencies_order(nodes, segments),
+    };
+    let mut out = String::new();
+    for node in nodes {
+        // moved segment may be not terminated by a newline
+        if order == Order::Dependencies && !out.is_empty() && !out.ends_with('\n') {
+            out.push('\n');
+        }
         match node {
-            Node::Content(content) => blocks.push(*content),
+            Node::Content { content, .. } => out.push_str(content),
             Node::Segment {
-                name,
                 content,
                 indentation,
                 ..
             } => {
-                if !segments.contains(name) {
-                    continue;
-                }
                 let l = indentation.len();
-                if l == 0 {
-                    blocks.push(content);
-                    continue;
+                let mut content = content.to_string();
+                if l > 0 {
+                    content = content
+                        .split_inclusive('\n')
+                        .map(|line| {
+                            line.get(l..)
+                                .unwrap_or(line.trim_start_matches([' ', '\t']))
+                        })
+                        .collect();
                 }
-                for line in content.lines() {
-                    blocks.push(&line[l..]);
-                    blocks.push("\n");
-                }
-                if !content.ends_with("\n") {
-                    blocks.pop();
+                let Some(interpreter) = node.interpreter() else {
+                    out.push_str(&content);
+                    continue;
+                };
+                let body = content.split_once('\n').map_or("", |(_, body)| body);
+                let delimiter = heredoc_delimiter(body);
+                out.push_str(&format!(
+                    "{interpreter} /dev/stdin \"$@\" <<'{delimiter}'\n{body}"
+                ));
+                if !body.is_empty() && !body.ends_with('\n') {
+                    out.push('\n');
                 }
+                out.push_str(&delimiter);
+                out.push('\n');
             }
         }
     }
-    Ok(blocks.join(""))
+    out
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::node::fixtures::{content, segment};
+
+    fn resolve<'a>(nodes: &[Node<'a>], targets: &[&'a str]) -> Result<String, MkError<'a>> {
+        let resolved = resolve_segments(nodes, targets, &Options::default())?;
+        Ok(render(nodes, &resolved.segments, Order::File))
+    }
 
     #[test]
     fn should_resolve_common_content() {
-        let nodes = &[Node::Content("common content")];
-        assert_eq!(resolve(nodes, &[]), Ok("common content".to_string()));
+        let nodes = &[content("common content")];
+        assert_eq!(resolve(nodes, &[]).unwrap(), "common content");
     }
 
     #[test]
     fn should_resolve_segment_content() {
-        let nodes = &[Node::Segment {
-            name: "foo",
-            content: "foo content",
-            dependencies: Vec::new(),
-            indentation: "",
-        }];
-        assert_eq!(resolve(nodes, &["foo"]), Ok("foo content".to_string()));
+        let nodes = &[segment("foo", "foo content", "", &[])];
+        assert_eq!(resolve(nodes, &["foo"]).unwrap(), "foo content");
     }
 
     #[test]
     fn should_resolve_all_content() {
         let nodes = &[
-            Node::Content("common content\n"),
-            Node::Segment {
-                name: "foo",
-                content: "foo content",
-                dependencies: Vec::new(),
-                indentation: "",
-            },
+            content("common content\n"),
+            segment("foo", "foo content", "", &[]),
         ];
         assert_eq!(
-            resolve(nodes, &["foo"]),
-            Ok("common content\nfoo content".to_string())
+            resolve(nodes, &["foo"]).unwrap(),
+            "common content\nfoo content"
         );
     }
 
     #[test]
     fn should_resolve_dependency() {
         let nodes = &[
-            Node::Segment {
-                name: "foo",
-                content: "foo content\n",
-                dependencies: Vec::new(),
-                indentation: "",
-            },
-            Node::Segment {
-                name: "bar",
-                content: "bar content",
-                dependencies: vec!["foo"],
-                indentation: "",
-            },
+            segment("foo", "foo content\n", "", &[]),
+            segment("bar", "bar content", "", &["foo"]),
         ];
         assert_eq!(
-            resolve(nodes, &["bar"]),
-            Ok("foo content\nbar content".to_string())
+            resolve(nodes, &["bar"]).unwrap(),
+            "foo content\nbar content"
         );
     }
 
     #[test]
     fn should_resolve_with_indentation() {
         let nodes = &[
-            Node::Segment {
-                name: "foo",
-                content: "\tfoo content\n",
-                dependencies: Vec::new(),
-                indentation: "\t",
-            },
-            Node::Segment {
-                name: "bar",
-                content: "    bar content",
-                dependencies: vec!["foo"],
-                indentation: "    ",
-            },
+            segment("foo", "\tfoo content\n", "\t", &[]),
+            segment("bar", "    bar content", "    ", &["foo"]),
         ];
         assert_eq!(
-            resolve(nodes, &["bar"]),
-            Ok("foo content\nbar content".to_string())
+            resolve(nodes, &["bar"]).unwrap(),
+            "foo content\nbar content"
         );
     }
 
     #[test]
     fn should_not_resolve() {
         let nodes = &[
-            Node::Content("common content"),
-            Node::Segment {
-                name: "foo",
-                content: "foo content",
-                dependencies: Vec::new(),
-                indentation: "",
-            },
+            content("common content"),
+            segment("foo", "foo content", "", &[]),
+        ];
+        assert!(matches!(
+            resolve(nodes, &["foo", "bar"]),
+            Err(MkError::UnknownTarget { target, .. }) if target == "bar"
+        ));
+    }
+
+    #[test]
+    fn should_report_dependency_cycle() {
+        let nodes = &[
+            segment("a", "", "", &["b"]),
+            segment("b", "", "", &["c"]),
+            segment("c", "", "", &["a"]),
+        ];
+        assert!(matches!(
+            resolve(nodes, &["a"]),
+            Err(MkError::DependencyCycle { path, .. }) if path == ["a", "b", "c", "a"]
+        ));
+        assert!(matches!(
+            resolve(&[segment("a", "", "", &["a"])], &["a"]),
+            Err(MkError::DependencyCycle { path, .. }) if path == ["a", "a"]
+        ));
+    }
+
+    #[test]
+    fn should_locate_dependency_cycle_at_its_edges() {
+        let sources = crate::syntax::fixtures::sources("a: b\nb: c\nb: a\nc:\n");
+        let content = crate::syntax::fixtures::desugar(&sources);
+        let nodes = crate::syntax::nodes(&content, &sources);
+        let Err(MkError::DependencyCycle { path, locations }) = resolve(&nodes, &["a"]) else {
+            panic!("cycle expected");
+        };
+        assert_eq!(path, ["a", "b", "a"]);
+        // the dependency of the later definition of `b` closes the cycle
+        let lines: Vec<_> = locations.iter().map(|l| (l.line, l.column)).collect();
+        assert_eq!(lines, vec![(1, 4), (3, 4)]);
+    }
+
+    #[test]
+    fn should_resolve_dependencies_of_every_definition() {
+        let nodes = &[
+            segment("a", "a1\n", "", &["b"]),
+            segment("a", "a2\n", "", &["c"]),
+            segment("b", "b\n", "", &[]),
+            segment("c", "c\n", "", &[]),
+        ];
+        assert_eq!(resolve(nodes, &["a"]).unwrap(), "a1\na2\nb\nc\n");
+    }
+
+    #[test]
+    fn should_resolve_shared_dependency() {
+        let nodes = &[
+            segment("a", "a\n", "", &[]),
+            segment("b", "b\n", "", &["a"]),
+            segment("c", "c\n", "", &["a", "b"]),
+        ];
+        assert_eq!(resolve(nodes, &["c"]).unwrap(), "a\nb\nc\n");
+    }
+
+    #[test]
+    fn should_warn_on_undefined_dependency() {
+        let nodes = &[
+            segment("g/foo", "", "", &["g", "bar"]),
+            segment("baz", "", "", &["f/*"]),
+        ];
+        let resolved = resolve_segments(nodes, &["g/foo", "baz"], &Options::default()).unwrap();
+        let undefined: Vec<_> = resolved
+            .warnings
+            .iter()
+            .filter_map(|w| match w {
+                MkError::UndefinedDependency { dependency, .. } => Some(dependency.as_str()),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(undefined, vec!["bar", "f/*"]);
+    }
+
+    #[test]
+    fn should_fail_on_undefined_dependency_in_strict_mode() {
+        let nodes = &[segment("foo", "", "", &["bar"])];
+        assert!(matches!(
+            resolve_segments(
+                nodes,
+                &["foo"],
+                &Options {
+                    strict: true,
+                    ..Options::default()
+                }
+            ),
+            Err(MkError::UndefinedDependency { segment, dependency, .. })
+                if segment == "foo" && dependency == "bar"
+        ));
+    }
+
+    #[test]
+    fn should_order_segments_after_dependencies() {
+        let nodes = &[
+            content("common\n"),
+            segment("bar", "bar\n", "", &["foo"]),
+            segment("baz", "baz", "", &["bar"]),
+            content("end\n"),
+            segment("foo", "foo\n", "", &[]),
+        ];
+        let resolved = resolve_segments(nodes, &["baz"], &Options::default()).unwrap();
+        assert_eq!(
+            render(nodes, &resolved.segments, Order::Dependencies),
+            "common\nend\nfoo\nbar\nbaz"
+        );
+    }
+
+    #[test]
+    fn should_keep_definitions_order_of_same_segment() {
+        let nodes = &[
+            segment("g", "pushd\n", "", &["dep"]),
+            segment("g/foo", "foo\n", "", &["g"]),
+            segment("g", "popd\n", "", &["dep"]),
+            segment("dep", "dep\n", "", &[]),
+        ];
+        let resolved = resolve_segments(nodes, &["g/foo"], &Options::default()).unwrap();
+        assert_eq!(
+            render(nodes, &resolved.segments, Order::Dependencies),
+            "dep\npushd\nfoo\npopd\n"
+        );
+    }
+
+    #[test]
+    fn should_postpone_group_with_its_members() {
+        let nodes = &[
+            segment("g", "pushd\n", "", &[]),
+            segment("g/foo", "foo\n", "", &["g", "dep", "g/bar"]),
+            segment("g/bar", "bar\n", "", &["g"]),
+            segment("g", "popd\n", "", &[]),
+            segment("dep", "dep\n", "", &[]),
+        ];
+        let resolved = resolve_segments(nodes, &["g/foo"], &Options::default()).unwrap();
+        assert_eq!(
+            render(nodes, &resolved.segments, Order::Dependencies),
+            "dep\npushd\nbar\nfoo\npopd\n"
+        );
+    }
+
+    #[test]
+    fn should_pass_annotated_segment_to_interpreter() {
+        let nodes = &[
+            content("#!/bin/sh\n"),
+            segment(
+                "foo",
+                "    #!python3\n    print(1)\n    # MK_SEGMENT\n    MK_SEGMENT\n",
+                "    ",
+                &[],
+            ),
+        ];
+        assert_eq!(
+            resolve(nodes, &["foo"]).unwrap(),
+            "#!/bin/sh\npython3 /dev/stdin \"$@\" <<'MK_SEGMENT_'\nprint(1)\n# MK_SEGMENT\nMK_SEGMENT\nMK_SEGMENT_\n"
+        );
+    }
+
+    #[test]
+    fn should_merge_dependencies_of_definitions_in_subgraph() {
+        let nodes = &[
+            segment("g", "pushd\n", "", &["dep"]),
+            segment("g/foo", "foo\n", "", &["g", "undefined"]),
+            segment("g", "popd\n", "", &["dep"]),
+            segment("dep", "dep\n", "", &[]),
+            segment("other", "other\n", "", &[]),
+        ];
+        let resolved = resolve_segments(nodes, &["g/foo"], &Options::default()).unwrap();
+        assert_eq!(
+            subgraph(nodes, &resolved.segments, &Sources::new(Vec::new())),
+            vec![
+                ("g", None, vec!["dep"]),
+                ("g/foo", None, vec!["g"]),
+                ("dep", None, vec![])
+            ]
+        );
+    }
+
+    #[test]
+    fn should_select_default_targets() {
+        let nodes = &[
+            segment("build", "    make\n", "    ", &[]),
+            segment("default", "    make all\n", "    ", &[]),
+        ];
+        assert_eq!(default_targets(nodes, Some("build")), vec!["build"]);
+        assert_eq!(default_targets(nodes, None), vec!["default"]);
+        let nodes = &[
+            segment("build", "    make\n", "    ", &[]),
+            segment("test", "    # @default\n    make test\n", "    ", &[]),
+        ];
+        assert_eq!(default_targets(nodes, Some("build")), vec!["test"]);
+        assert!(default_targets(&nodes[..1], None).is_empty());
+    }
+
+    #[test]
+    fn should_resolve_abbreviations() {
+        let nodes = &[
+            segment("app/ppp/build", "build\n", "", &[]),
+            segment("app/ppp/deps", "deps\n", "", &[]),
+            segment("app/ppp/dev", "dev\n", "", &[]),
+            segment("api/build", "api\n", "", &[]),
+        ];
+        let options = Options {
+            abbreviations: true,
+            ..Options::default()
+        };
+        let resolved = resolve_segments(nodes, &["a/p/b"], &options).unwrap();
+        assert_eq!(resolved.segments, HashSet::from(["app/ppp/build"]));
+        assert!(matches!(
+            resolve_segments(nodes, &["a/p/b"], &Options::default()),
+            Err(MkError::UnknownTarget { .. })
+        ));
+        let error = resolve_segments(nodes, &["a/p/d"], &options).err().unwrap();
+        assert_eq!(
+            error.to_string(),
+            "target `a/p/d` is ambiguous: app/ppp/deps, app/ppp/dev"
+        );
+    }
+
+    #[test]
+    fn should_exclude_targets_before_resolution() {
+        let nodes = &[
+            segment("p/a/build", "a\n", "", &[]),
+            segment("p/b/build", "b\n", "", &[]),
+            segment("p/c/build", "c\n", "", &["p/b/build"]),
+        ];
+        assert_eq!(
+            resolve(nodes, &["p/*/build", "!p/[bc]/build"]).unwrap(),
+            "a\n"
+        );
+        assert_eq!(
+            resolve(nodes, &["p/*/build", "!p/b/build"]).unwrap(),
+            "a\nb\nc\n"
+        );
+    }
+
+    #[test]
+    fn should_limit_dependencies() {
+        let nodes = &[
+            segment("g", "pushd\n", "", &[]),
+            segment("g/check", "check\n", "", &["g"]),
+            segment("g/build", "build\n", "", &["g", "g/check"]),
+            segment("g/preview", "preview\n", "", &["g", "g/build"]),
+            segment("g", "popd\n", "", &[]),
         ];
-        assert_eq!(resolve(nodes, &["foo", "bar"]), Err("bar"));
+        let render_with = |depth, only_dependencies, order| {
+            let options = Options {
+                depth,
+                only_dependencies,
+                ..Options::default()
+            };
+            let resolved = resolve_segments(nodes, &["g/preview"], &options).unwrap();
+            render(nodes, &resolved.segments, order)
+        };
+        let resolve = |depth, only_dependencies| render_with(depth, only_dependencies, Order::File);
+        let reorder = |depth| render_with(depth, false, Order::Dependencies);
+        assert_eq!(resolve(Some(0), false), "pushd\npreview\npopd\n");
+        assert_eq!(resolve(Some(1), false), "pushd\nbuild\npreview\npopd\n");
+        assert_eq!(resolve(None, true), "pushd\ncheck\nbuild\npopd\n");
+        assert_eq!(resolve(Some(1), true), "pushd\nbuild\npopd\n");
+        assert_eq!(reorder(Some(0)), "pushd\npreview\npopd\n");
+        assert_eq!(reorder(Some(1)), "pushd\nbuild\npreview\npopd\n");
     }
 }
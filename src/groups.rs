@@ -4,6 +4,7 @@ use super::chars::*;
 use super::dependencies_collector::DependenciesCollector;
 use super::node::Node;
 use super::segments_scanner::SegmentsScanner;
+use super::source::{LineMap, MappedText, Origin, line_column, line_starts, lines_count, span_map};
 
 #[derive(Debug, PartialEq)]
 struct Position {
@@ -61,32 +62,35 @@ fn get_group_len(content: &str, indentation: &str) -> usize {
     }
 }
 
-fn build_group_header(prefix: &str, content: &str, length: usize, deps: Vec<&str>) -> String {
-    let d_list: String = if prefix.is_empty() {
-        iter::once("")
-            .chain(deps.into_iter())
-            .map(|d| {
-                if d.starts_with("/") {
-                    return &d[1..];
-                }
-                d
-            })
-            .collect::<Vec<_>>()
-            .join(" ")
-    } else {
-        deps.into_iter()
-            .flat_map(|d| {
-                if d.starts_with("/") {
-                    vec![" ", d].into_iter()
-                } else {
-                    vec![" ", prefix, "/", d].into_iter()
-                }
-            })
-            .collect::<Vec<_>>()
-            .join("")
-    };
-    // omit root slashes
-    return vec![&content[..length - 1], ":", d_list.as_str()].join("");
+/// Mark and name of a dependency, an exclusion `!name` is
+/// resolved against the groups like the name it excludes
+fn split_exclusion(dependency: &str) -> (&str, &str) {
+    match dependency.strip_prefix('!') {
+        Some(name) => ("!", name),
+        None => ("", dependency),
+    }
+}
+
+/// Dependencies of the group header with its prefix, root slashes omitted
+fn group_header_dependencies(prefix: &str, deps: &[(&str, Origin)]) -> Vec<(String, Origin)> {
+    deps.iter()
+        .map(|(d, origin)| {
+            let (mark, d) = split_exclusion(d);
+            let dependency = if prefix.is_empty() {
+                format!("{mark}{}", d.strip_prefix("/").unwrap_or(d))
+            } else if d.starts_with("/") {
+                format!("{mark}{d}")
+            } else {
+                format!("{mark}{prefix}/{d}")
+            };
+            (dependency, *origin)
+        })
+        .collect()
+}
+
+fn push_header(result: &mut MappedText, name: &str, deps: &[(String, Origin)], origin: Origin) {
+    let deps: Vec<_> = deps.iter().map(|(d, o)| (d.as_str(), *o, None)).collect();
+    result.push_header(name, &deps, origin);
 }
 
 fn remove_parent_indentation(content: &str, indentation_len: usize) -> String {
@@ -103,41 +107,105 @@ fn remove_parent_indentation(content: &str, indentation_len: usize) -> String {
 struct DesugaredNode {
     name: String,
     content: String,
-    dependencies: Vec<String>,
+    // dependencies with the origin of each of them
+    dependencies: Vec<(String, Origin)>,
+    // origin of the header line and of the content lines
+    header: Origin,
+    map: LineMap,
 }
 
-fn desugar_groups(content: &str, prefix: &str) -> String {
-    let group_start = find_group_start(content);
-    if group_start.is_none() {
-        return content.to_string();
+#[derive(Debug, PartialEq)]
+pub struct IndentationError {
+    pub group: String,
+    pub origin: Origin,
+}
+
+impl IndentationError {
+    fn rebase(self, map: &LineMap) -> Self {
+        Self {
+            group: self.group,
+            origin: map.locate(self.origin),
+        }
     }
-    let Position { start, length } = group_start.unwrap();
+}
+
+/// Content right after the group should not be indented
+fn check_group_end(content: &str, group: &str, origin: Origin) -> Result<(), IndentationError> {
+    let line = content.lines().next().unwrap_or_default();
+    if line.starts_with(char::is_whitespace) && !line.trim().is_empty() {
+        return Err(IndentationError {
+            group: group.to_string(),
+            origin,
+        });
+    }
+    Ok(())
+}
+
+fn desugar_groups(content: &str, prefix: &str) -> Result<MappedText, IndentationError> {
+    let Some(Position { start, length }) = find_group_start(content) else {
+        return Ok(MappedText::new(content));
+    };
+    let content_line_starts = line_starts(content);
+    let position = |offset: usize| {
+        let (line, column) = line_column(&content_line_starts, offset);
+        Origin { line, column }
+    };
+    let origin = |offset: usize| Origin {
+        column: 0,
+        ..position(offset)
+    };
+    let rebased = |offset: usize, text: Result<MappedText, IndentationError>| {
+        let map = LineMap::lines(origin(offset), lines_count(&content[offset..]));
+        text.map(|text| MappedText {
+            map: map.compose(&text.map),
+            content: text.content,
+        })
+        .map_err(|e| e.rebase(&map))
+    };
+    let mut result = MappedText::default();
+    result.push_lines(&content[..start], origin(0));
+    let header = origin(start);
+    let group_name = &content[start..start + length - 1];
     let deps_start = start + length + 1;
-    let (len, group_dependencies) = DependenciesCollector::new(&content[deps_start..]).collect();
+    let (len, group_dependencies) =
+        DependenciesCollector::new(&content[deps_start..], deps_start).collect();
+    let group_dependencies: Vec<_> = group_dependencies
+        .into_iter()
+        .map(|d| (d.name, position(d.span.start)))
+        .collect();
     let group_content_start = deps_start + len + 1;
     // end of file (no group content)
     if group_content_start >= content.len() {
-        return build_group_header(prefix, content, length, group_dependencies);
+        let deps = group_header_dependencies(prefix, &group_dependencies);
+        push_header(&mut result, group_name, &deps, header);
+        return Ok(result);
     }
     let group_indentation = detect_group_indentation(&content[group_content_start..]);
     // empty group
-    if group_indentation.is_none() {
-        return vec![
-            build_group_header(prefix, content, length, group_dependencies).as_str(),
-            "\n",
-            desugar_groups(&content[group_content_start..], prefix).as_str(),
-        ]
-        .join("");
-    }
-    let group_indentation = group_indentation.unwrap();
+    let Some(group_indentation) = group_indentation else {
+        let deps = group_header_dependencies(prefix, &group_dependencies);
+        push_header(&mut result, group_name, &deps, header);
+        result.push_lines("\n", header);
+        result.append(rebased(
+            group_content_start,
+            desugar_groups(&content[group_content_start..], prefix),
+        )?);
+        return Ok(result);
+    };
     let group_content_len = get_group_len(&content[group_content_start..], group_indentation);
     let group_content_end = group_content_start + group_content_len;
-    let group_name = &content[start..start + length - 1];
     let group_name_with_prefix = if prefix.is_empty() {
         group_name.to_owned()
     } else {
         format!("{prefix}/{group_name}")
     };
+    let group_base_map = LineMap::lines(
+        Origin {
+            line: origin(group_content_start).line,
+            column: group_indentation.len(),
+        },
+        lines_count(&content[group_content_start..group_content_end]),
+    );
     let group_content = desugar_groups(
         remove_parent_indentation(
             &content[group_content_start..group_content_end],
@@ -145,81 +213,107 @@ fn desugar_groups(content: &str, prefix: &str) -> String {
         )
         .as_str(),
         group_name_with_prefix.as_str(),
-    );
+    )
+    .map_err(|e| e.rebase(&group_base_map))?;
+    let group_map = group_base_map.compose(&group_content.map);
+    let group_line_starts = line_starts(&group_content.content);
+    // the group a segment depends on is written in the header of the group
+    let group = (group_name.to_string(), header);
     let handle_prefix = |s: &str| {
+        let (mark, s) = split_exclusion(s);
         if !s.starts_with("/") {
             return None;
         }
         Some(if prefix.is_empty() {
-            s[1..].to_string()
+            format!("{mark}{}", &s[1..])
         } else {
-            s.to_string()
+            format!("{mark}{s}")
         })
     };
-    let segments = iter::once(content[..start].to_string()).chain(
-        SegmentsScanner::new(group_content.as_str())
-            .map(|node| match node {
-                Node::Content(content) => {
-                    let lines = content.lines().map(|l| format!("{group_indentation}{l}"));
-                    DesugaredNode {
-                        name: group_name.to_string(),
-                        content: if content.ends_with("\n") {
-                            lines
-                                .chain(iter::once("".to_string()))
-                                .collect::<Vec<_>>()
-                                .join("\n")
-                        } else {
-                            lines.collect::<Vec<_>>().join("\n")
-                        },
-                        dependencies: iter::once("".to_string())
-                            .chain(group_dependencies.iter().map(|d| d.to_string()))
-                            .collect(),
-                    }
-                }
-                Node::Segment {
-                    name,
-                    content,
-                    dependencies,
-                    ..
-                } => DesugaredNode {
-                    name: format!("{group_name}/{name}"),
-                    content: content.to_string(),
-                    dependencies: iter::once("".to_string())
-                        .chain(iter::once(group_name.to_string()))
-                        .chain(
-                            group_dependencies
-                                .iter()
-                                .map(|dep| handle_prefix(dep).unwrap_or_else(|| dep.to_string())),
-                        )
-                        .chain(dependencies.into_iter().map(|dep| {
-                            handle_prefix(dep).unwrap_or_else(|| format!("{group_name}/{dep}"))
-                        }))
-                        .collect(),
+    let nodes = SegmentsScanner::new(group_content.content.as_str()).map(|node| match node {
+        Node::Content { content, span, .. } => {
+            let lines = content.lines().map(|l| format!("{group_indentation}{l}"));
+            let mut map = span_map(&group_line_starts, &group_map, span);
+            // indentation is restored
+            map.restore_indentation(group_indentation.len());
+            DesugaredNode {
+                name: group_name.to_string(),
+                content: if content.ends_with("\n") {
+                    lines
+                        .chain(iter::once("".to_string()))
+                        .collect::<Vec<_>>()
+                        .join("\n")
+                } else {
+                    lines.collect::<Vec<_>>().join("\n")
                 },
-            })
-            .map(
-                |DesugaredNode {
-                     name,
-                     content,
-                     dependencies,
-                 }| format!("{}:{}\n{}", name, dependencies.join(" "), content),
-            ),
-    );
+                dependencies: group_dependencies
+                    .iter()
+                    .map(|(d, origin)| (d.to_string(), *origin))
+                    .collect(),
+                header,
+                map,
+            }
+        }
+        Node::Segment {
+            name,
+            content,
+            dependencies,
+            span,
+            ..
+        } => {
+            let map = span_map(&group_line_starts, &group_map, span);
+            DesugaredNode {
+                name: format!("{group_name}/{name}"),
+                content: content.to_string(),
+                dependencies: iter::once(group.clone())
+                    .chain(group_dependencies.iter().map(|(dep, origin)| {
+                        let dep = handle_prefix(dep).unwrap_or_else(|| dep.to_string());
+                        (dep, *origin)
+                    }))
+                    .chain(dependencies.into_iter().map(|dep| {
+                        let (line, column) = line_column(&group_line_starts, dep.span.start);
+                        let origin = group_map.locate(Origin { line, column });
+                        let dep = handle_prefix(dep.name).unwrap_or_else(|| {
+                            let (mark, name) = split_exclusion(dep.name);
+                            format!("{mark}{group_name}/{name}")
+                        });
+                        (dep, origin)
+                    }))
+                    .collect(),
+                header: map.get(0),
+                map: map.slice(1, lines_count(content)),
+            }
+        }
+    });
+    for DesugaredNode {
+        name,
+        content,
+        dependencies,
+        header,
+        map,
+    } in nodes
+    {
+        push_header(&mut result, &name, &dependencies, header);
+        result.push_lines("\n", header);
+        result.push(&content, &map);
+    }
     if group_content_end < content.len() {
-        segments
-            .chain(iter::once("\n".to_string()))
-            .chain(iter::once(desugar_groups(
-                &content[group_content_end..],
-                prefix,
-            )))
-            .collect::<Vec<_>>()
-            .join("")
-    } else {
-        segments.collect::<Vec<_>>().join("")
+        check_group_end(
+            &content[group_content_end..],
+            &group_name_with_prefix,
+            origin(group_content_end),
+        )?;
+        result.push_lines("\n", origin(group_content_end));
+        result.append(rebased(
+            group_content_end,
+            desugar_groups(&content[group_content_end..], prefix),
+        )?);
     }
+    Ok(result)
 }
 
-pub fn desugar(content: &str) -> String {
+/// Desugared content and the origin of each of its lines
+pub fn desugar(content: &str) -> Result<MappedText, IndentationError> {
     desugar_groups(content, "")
 }
 
@@ -268,12 +362,18 @@ mod tests {
 
     #[test]
     fn should_desugar_empty_group() {
-        assert_eq!(desugar("group/: dep /root-dep"), "group: dep root-dep")
+        assert_eq!(
+            desugar("group/: dep /root-dep").unwrap().content,
+            "group: dep root-dep"
+        )
     }
 
     #[test]
     fn should_desugar_simple_group() {
-        assert_eq!(desugar("group/:\n\tcontent"), "group:\n\tcontent")
+        assert_eq!(
+            desugar("group/:\n\tcontent").unwrap().content,
+            "group:\n\tcontent"
+        )
     }
 
     #[test]
@@ -291,7 +391,9 @@ group/:
         baz content
         
     popd"
-            ),
+            )
+            .unwrap()
+            .content,
             "
 group:
     pushd folder
@@ -320,7 +422,9 @@ a/:
     popd
   build: go/build
 "
-            ),
+            )
+            .unwrap()
+            .content,
             "# Artifacts
 a/go: a
   pushd packages/testing-go/go
@@ -337,7 +441,7 @@ a/build: a a/go/build
     fn should_not_panic_while_desugar_file() {
         let content = include_str!("testdata/out-of-bounds.input");
         let result = std::panic::catch_unwind(|| {
-            desugar(content);
+            let _ = desugar(content);
         });
         assert!(result.is_ok(), "should not panic");
     }
@@ -349,9 +453,86 @@ a/build: a a/go/build
                 "t/: /root vars
   plan:
     tofu plan ${vars}"
-            ),
+            )
+            .unwrap()
+            .content,
             "t/plan: t root vars
   tofu plan ${vars}"
         );
     }
+
+    #[test]
+    fn should_resolve_exclusions_like_dependencies() {
+        assert_eq!(
+            desugar("p/: /p/* !/p/legacy\n  build: * !old\n    make")
+                .unwrap()
+                .content,
+            "p/build: p p/* !p/legacy p/* !p/old\n  make"
+        );
+    }
+
+    #[test]
+    fn should_keep_content_before_empty_group() {
+        assert_eq!(
+            desugar("foo:\n  foo\ng/: foo").unwrap().content,
+            "foo:\n  foo\ng: foo"
+        );
+    }
+
+    #[test]
+    fn should_map_desugared_lines_to_origin() {
+        let MappedText { content, map } = desugar(
+            "common
+g/: dep
+  pushd g
+  bar:
+    bar content
+  popd
+end",
+        )
+        .unwrap();
+        assert_eq!(
+            content,
+            "common
+g: dep
+  pushd g
+g/bar: g dep
+  bar content
+g: dep
+  popd
+end"
+        );
+        let lines: Vec<_> = (0..8).map(|l| map.get(l)).collect();
+        assert_eq!(
+            lines,
+            vec![
+                Origin { line: 0, column: 0 },
+                Origin { line: 1, column: 0 },
+                Origin { line: 2, column: 0 },
+                Origin { line: 3, column: 2 },
+                Origin { line: 4, column: 2 },
+                Origin { line: 1, column: 0 },
+                Origin { line: 5, column: 0 },
+                Origin { line: 6, column: 0 },
+            ]
+        );
+    }
+
+    #[test]
+    fn should_reject_partially_indented_group_end() {
+        assert_eq!(
+            desugar("g/:\n    bar:\n        bar content\n  baz:\n"),
+            Err(IndentationError {
+                group: "g".to_string(),
+                origin: Origin { line: 3, column: 0 }
+            })
+        );
+        assert_eq!(
+            desugar("a/:\n  b/:\n    foo:\n      foo\n   bar:\n"),
+            Err(IndentationError {
+                group: "a/b".to_string(),
+                origin: Origin { line: 4, column: 2 }
+            })
+        );
+    }
 }
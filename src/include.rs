@@ -0,0 +1,272 @@
+use std::io;
+
+use super::chars::is_valid_segment_name_char;
+use super::error::MkError;
+use super::source::{LineMap, MappedText, Origin, SourceFile, Sources};
+
+// Indentation of the content of a file mounted under a group
+const MOUNT_INDENTATION: &str = "    ";
+
+/// Path and the group of an `include <path> [as <group>/]` line
+pub fn directive(line: &str) -> Option<(&str, Option<&str>)> {
+    let mut words = line.strip_prefix("include ")?.split_whitespace();
+    let path = words.next()?;
+    match (words.next(), words.next(), words.next()) {
+        (None, _, _) => Some((path, None)),
+        (Some("as"), Some(group), None) => {
+            let group = group.strip_suffix('/').unwrap_or(group);
+            let valid = group.chars().next()?.is_alphabetic()
+                && group.chars().all(is_valid_segment_name_char);
+            valid.then_some((path, Some(group)))
+        }
+        _ => None,
+    }
+}
+
+/// Path with `.` and `..` components resolved where possible
+fn normalize(path: &str) -> String {
+    let mut parts: Vec<&str> = Vec::new();
+    for part in path.split('/') {
+        match part {
+            "." => {}
+            ".." if parts.last().is_some_and(|p| !p.is_empty() && *p != "..") => {
+                parts.pop();
+            }
+            _ => parts.push(part),
+        }
+    }
+    parts.join("/")
+}
+
+/// Name of the file included by `path` from the file named `including`
+fn resolve(including: &str, path: &str) -> String {
+    if path.starts_with('/') {
+        return normalize(path);
+    }
+    let dir = including.rfind('/').map_or("", |i| &including[..=i]);
+    normalize(&format!("{dir}{path}"))
+}
+
+fn find(sources: &Sources, name: &str) -> Option<usize> {
+    sources
+        .files()
+        .iter()
+        .position(|file| normalize(&file.name) == name)
+}
+
+/// Adds the files included by the sources, and by the included files, to them
+pub fn read(sources: &mut Sources, read: impl Fn(&str) -> io::Result<String>) {
+    let mut i = 0;
+    while let Some(file) = sources.files().get(i) {
+        let names: Vec<_> = file
+            .content
+            .split('\n')
+            .filter_map(directive)
+            .map(|(path, _)| resolve(&file.name, path))
+            .collect();
+        for name in names {
+            if find(sources, &name).is_some() || sources.unreadable(&name).is_some() {
+                continue;
+            }
+            match read(&name) {
+                Ok(content) => sources.include(SourceFile { name, content }),
+                Err(error) => sources.mark_unreadable(name, error.to_string()),
+            }
+        }
+        i += 1;
+    }
+}
+
+fn expand<'a>(
+    sources: &'a Sources,
+    file: usize,
+    indentation: &str,
+    stack: &mut Vec<usize>,
+    lines: &mut Vec<(String, Origin)>,
+) -> Result<(), MkError<'a>> {
+    stack.push(file);
+    let first = sources.first_line(file);
+    let SourceFile { name, content } = &sources.files()[file];
+    for (n, line) in content.split('\n').enumerate() {
+        // the script runs with the interpreter of the input file
+        if n == 0 && stack.len() > 1 && line.starts_with("#!") {
+            continue;
+        }
+        let origin = Origin {
+            line: first + n,
+            column: indentation.len(),
+        };
+        let Some((path, group)) = directive(line) else {
+            lines.push((format!("{indentation}{line}"), origin));
+            continue;
+        };
+        let location = sources.locate(first + n, 0);
+        let included = resolve(name, path);
+        let Some(i) = find(sources, &included) else {
+            return Err(MkError::BadInclude {
+                reason: sources
+                    .unreadable(&included)
+                    .unwrap_or("not read")
+                    .to_string(),
+                path: included,
+                location,
+            });
+        };
+        if stack.contains(&i) {
+            return Err(MkError::IncludeCycle {
+                path: stack
+                    .iter()
+                    .map(|f| sources.files()[*f].name.clone())
+                    .chain(std::iter::once(included))
+                    .collect(),
+                location,
+            });
+        }
+        match group {
+            // mounted the way a group nests its segments
+            Some(group) => {
+                lines.push((format!("{indentation}{group}/:"), origin));
+                let nested = format!("{indentation}{MOUNT_INDENTATION}");
+                expand(sources, i, &nested, stack, lines)?;
+            }
+            None => expand(sources, i, indentation, stack, lines)?,
+        }
+    }
+    stack.pop();
+    Ok(())
+}
+
+/// Input files with the include directives replaced by the included content.
+/// The map holds the indentation added to each line as its column.
+pub fn desugar(sources: &Sources) -> Result<MappedText, MkError<'_>> {
+    if !sources
+        .content()
+        .split('\n')
+        .any(|line| directive(line).is_some())
+    {
+        return Ok(MappedText::new(sources.content()));
+    }
+    let mut lines = Vec::new();
+    for file in 0..sources.inputs() {
+        expand(sources, file, "", &mut Vec::new(), &mut lines)?;
+    }
+    let mut map = LineMap::default();
+    let mut content = Vec::with_capacity(lines.len());
+    for (line, origin) in lines {
+        content.push(line);
+        map.push(origin);
+    }
+    Ok(MappedText {
+        content: content.join("\n"),
+        map,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::syntax::{self, fixtures};
+
+    /// Input `mkfile` with the files it can include
+    fn read_sources(content: &str, included: &[(&str, &str)]) -> Sources {
+        let mut sources = fixtures::sources(content);
+        read(&mut sources, |name| {
+            included
+                .iter()
+                .find(|(n, _)| *n == name)
+                .map(|(_, content)| content.to_string())
+                .ok_or_else(|| io::Error::from(io::ErrorKind::NotFound))
+        });
+        sources
+    }
+
+    #[test]
+    fn should_parse_directive() {
+        assert_eq!(directive("include a/mkfile"), Some(("a/mkfile", None)));
+        assert_eq!(
+            directive("include a/mkfile as a/"),
+            Some(("a/mkfile", Some("a")))
+        );
+        assert_eq!(directive("include a/mkfile to a/"), None);
+        assert_eq!(directive("  include a/mkfile"), None);
+    }
+
+    #[test]
+    fn should_resolve_relative_to_including_file() {
+        assert_eq!(resolve("mkfile", "app/mkfile"), "app/mkfile");
+        assert_eq!(resolve("app/mkfile", "../lib/mkfile"), "lib/mkfile");
+        assert_eq!(resolve("app/mkfile", "/etc/mkfile"), "/etc/mkfile");
+        assert_eq!(resolve("../mkfile", "./x"), "../x");
+    }
+
+    #[test]
+    fn should_include_in_place() {
+        let sources = read_sources(
+            "a:\n    echo a\ninclude lib/mkfile\nc: b",
+            &[("lib/mkfile", "b:\n    echo b")],
+        );
+        let content = fixtures::desugar(&sources);
+        assert_eq!(content.content, "a:\n    echo a\nb:\n    echo b\nc: b\n");
+    }
+
+    #[test]
+    fn should_mount_under_group() {
+        let sources = read_sources(
+            "include app/mkfile as app/\nall: app/build\n",
+            &[("app/mkfile", "build: lib\n  make\nlib:\n  make lib")],
+        );
+        let content = fixtures::desugar(&sources);
+        assert_eq!(
+            content.content,
+            "app/build: app app/lib\n  make\napp/lib: app\n  make lib\nall: app/build\n"
+        );
+    }
+
+    #[test]
+    fn should_drop_shebang_of_included_file() {
+        let sources = read_sources(
+            "#!/bin/sh\ninclude pkg/mkfile as pkg/\n",
+            &[(
+                "pkg/mkfile",
+                "#!/usr/bin/env bash\nset -e\nbuild:\n    echo build",
+            )],
+        );
+        let content = fixtures::desugar(&sources);
+        assert_eq!(
+            content.content,
+            "#!/bin/sh\npkg:\n    set -e\npkg/build: pkg\n    echo build"
+        );
+    }
+
+    #[test]
+    fn should_locate_in_mounted_file() {
+        let sources = read_sources(
+            "include app/mkfile as app/\n",
+            &[("app/mkfile", "build: [a\n")],
+        );
+        let error = syntax::desugar(&sources, &Default::default()).unwrap_err();
+        let location = error.locations()[0].0;
+        assert_eq!((location.file, location.line), ("app/mkfile", 1));
+    }
+
+    #[test]
+    fn should_detect_include_cycle() {
+        let sources = read_sources("include a/mkfile", &[("a/mkfile", "include ../mkfile")]);
+        let error = syntax::desugar(&sources, &Default::default()).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "include cycle: mkfile -> a/mkfile -> mkfile"
+        );
+    }
+
+    #[test]
+    fn should_report_unreadable_include() {
+        let sources = read_sources("a:\ninclude b/mkfile", &[]);
+        let error = syntax::desugar(&sources, &Default::default()).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "cannot include b/mkfile: entity not found"
+        );
+        assert_eq!(error.locations()[0].0.line, 2);
+    }
+}
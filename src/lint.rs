@@ -0,0 +1,304 @@
+use std::collections::HashSet;
+
+use crate::error::MkError;
+use crate::graph;
+use crate::node::Node;
+use crate::parameters;
+use crate::source::{Locator, MappedText, Sources};
+use crate::syntax;
+
+/// Lines of the text with their offsets
+fn lines(text: &str) -> impl Iterator<Item = (usize, &str)> {
+    text.split_inclusive('\n').scan(0, |offset, line| {
+        let start = *offset;
+        *offset += line.len();
+        Some((start, line.trim_end_matches(['\n', '\r'])))
+    })
+}
+
+/// Name of a line written like a header that the scanner can't take for one
+fn invalid_header(line: &str) -> Option<&str> {
+    let (name, rest) = line.split_once(':')?;
+    let first = name.chars().next()?;
+    if first == '#' || name.contains(char::is_whitespace) {
+        return None;
+    }
+    if !rest.is_empty() && !rest.starts_with(char::is_whitespace) {
+        return None;
+    }
+    if parameters::is_segment_name(name) {
+        return None;
+    }
+    Some(name)
+}
+
+fn unreachable_segments<'a>(nodes: &[Node<'a>], problems: &mut Vec<MkError<'a>>) {
+    let documented: Vec<_> = nodes
+        .iter()
+        .filter(|node| node.description().is_some())
+        .filter_map(|node| match node {
+            Node::Segment { name, .. } => Some(*name),
+            Node::Content { .. } => None,
+        })
+        .collect();
+    // without documented targets every segment is meant to be run directly
+    if documented.is_empty() {
+        return;
+    }
+    let reachable = match graph::resolve_segments(nodes, &documented, &graph::Options::default()) {
+        Ok(resolved) => resolved.segments,
+        Err(error) => return problems.push(error),
+    };
+    let mut reported = HashSet::new();
+    for node in nodes {
+        if let Node::Segment { name, location, .. } = node
+            && !reachable.contains(name)
+            && reported.insert(*name)
+        {
+            problems.push(MkError::UnreachableSegment {
+                segment: name.to_string(),
+                location: *location,
+            });
+        }
+    }
+}
+
+fn undefined_dependencies<'a>(nodes: &[Node<'a>], problems: &mut Vec<MkError<'a>>) {
+    let names: HashSet<_> = nodes
+        .iter()
+        .filter_map(|node| match node {
+            Node::Segment { name, .. } => Some(*name),
+            Node::Content { .. } => None,
+        })
+        .collect();
+    for node in nodes {
+        let Node::Segment {
+            name, dependencies, ..
+        } = node
+        else {
+            continue;
+        };
+        for dep in dependencies {
+            if !names.contains(dep.name) && !graph::is_parent_group(dep.name, name) {
+                problems.push(MkError::UndefinedDependency {
+                    segment: name.to_string(),
+                    dependency: dep.name.to_string(),
+                    location: dep.location,
+                });
+            }
+        }
+    }
+}
+
+fn invalid_headers<'a>(
+    content: &'a str,
+    locator: &Locator<'_, 'a>,
+    problems: &mut Vec<MkError<'a>>,
+) {
+    // the scanner may take the rest of such a line for a header,
+    // so the lines are checked regardless of the scanned nodes
+    for (offset, line) in lines(content) {
+        if let Some(name) = invalid_header(line) {
+            problems.push(MkError::InvalidHeader {
+                name: name.to_string(),
+                location: locator.locate(offset),
+            });
+        }
+    }
+}
+
+fn mixed_indentation<'a>(
+    nodes: &[Node<'a>],
+    locator: &Locator<'_, 'a>,
+    problems: &mut Vec<MkError<'a>>,
+) {
+    for node in nodes {
+        let Node::Segment {
+            name,
+            content,
+            span,
+            ..
+        } = node
+        else {
+            continue;
+        };
+        let start = span.end - content.len();
+        let (mut tabs, mut spaces) = (false, false);
+        for (offset, line) in lines(content) {
+            let text = line.trim_start_matches([' ', '\t']);
+            if text.is_empty() {
+                continue;
+            }
+            let indentation = &line[..line.len() - text.len()];
+            tabs |= indentation.contains('\t');
+            spaces |= indentation.contains(' ');
+            if tabs && spaces {
+                problems.push(MkError::MixedIndentation {
+                    segment: name.to_string(),
+                    width: indentation.chars().count(),
+                    location: locator.locate(start + offset),
+                });
+                break;
+            }
+        }
+    }
+}
+
+fn unmatched_pushd<'a>(
+    nodes: &[Node<'a>],
+    locator: &Locator<'_, 'a>,
+    problems: &mut Vec<MkError<'a>>,
+) {
+    let names: Vec<_> = nodes
+        .iter()
+        .filter_map(|node| match node {
+            Node::Segment { name, .. } => Some(*name),
+            Node::Content { .. } => None,
+        })
+        .collect();
+    let groups: Vec<_> = names
+        .iter()
+        .filter(|group| names.iter().any(|name| graph::is_parent_group(group, name)))
+        .collect::<HashSet<_>>()
+        .into_iter()
+        .collect();
+    for group in groups {
+        // a group content is split into the definitions before and after its segments
+        let mut pushed = Vec::new();
+        for node in nodes {
+            let Node::Segment {
+                name,
+                content,
+                span,
+                ..
+            } = node
+            else {
+                continue;
+            };
+            if name != group {
+                continue;
+            }
+            let start = span.end - content.len();
+            for (offset, line) in lines(content) {
+                let text = line.trim_start();
+                let column = line.len() - text.len();
+                match text.split_whitespace().next() {
+                    Some("pushd") => pushed.push(locator.locate(start + offset + column)),
+                    Some("popd") => {
+                        pushed.pop();
+                    }
+                    _ => {}
+                }
+            }
+        }
+        for location in pushed {
+            problems.push(MkError::UnmatchedPushd {
+                group: group.to_string(),
+                location,
+            });
+        }
+    }
+}
+
+/// Problems found in the mkfiles ordered by their location
+pub fn lint<'a>(content: &'a MappedText, sources: &'a Sources) -> Vec<MkError<'a>> {
+    let locator = Locator::new(&content.content, &content.map, sources);
+    let nodes = syntax::nodes(content, sources);
+    let mut problems = Vec::new();
+    unreachable_segments(&nodes, &mut problems);
+    undefined_dependencies(&nodes, &mut problems);
+    invalid_headers(&content.content, &locator, &mut problems);
+    mixed_indentation(&nodes, &locator, &mut problems);
+    unmatched_pushd(&nodes, &locator, &mut problems);
+    problems.sort_by_key(|problem| {
+        problem
+            .locations()
+            .first()
+            .map(|(location, _)| (location.file, location.line, location.column))
+    });
+    problems
+}
+
+/// Reports the problems as warnings, or as errors failing the run if `deny` is set
+pub fn run<'a>(
+    content: &'a MappedText,
+    sources: &'a Sources,
+    deny: bool,
+) -> Result<(), MkError<'a>> {
+    let problems = lint(content, sources);
+    for problem in &problems {
+        if deny {
+            eprint!("{}", problem.render(Some(sources)));
+        } else {
+            eprint!("{}", problem.render_warning(Some(sources)));
+        }
+    }
+    if deny && !problems.is_empty() {
+        return Err(MkError::LintFailed {
+            count: problems.len(),
+        });
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::syntax::fixtures;
+
+    fn messages(content: &str) -> Vec<String> {
+        let sources = fixtures::sources(content);
+        let content = fixtures::desugar(&sources);
+        lint(&content, &sources)
+            .iter()
+            .map(|problem| {
+                let location = problem.locations()[0].0;
+                format!("{}:{} {problem}", location.line, location.column)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn should_find_unreachable_segments() {
+        assert_eq!(
+            messages(
+                "build: lib\n    # builds all\n    make\nlib:\n    make lib\nold:\n    make old\n"
+            ),
+            ["6:1 `old` is not reachable from any documented target"]
+        );
+    }
+
+    #[test]
+    fn should_find_undefined_dependencies() {
+        assert_eq!(
+            messages("build: lib\n    make\n"),
+            ["1:8 `build` depends on undefined segment `lib`"]
+        );
+    }
+
+    #[test]
+    fn should_find_headers_parsed_as_content() {
+        assert_eq!(
+            messages("baz:\n    make\nfoo@bar: baz\n    make\n# note:\n"),
+            ["3:1 `foo@bar:` is not a segment header: `@` is not allowed in segment names"]
+        );
+    }
+
+    #[test]
+    fn should_find_mixed_indentation() {
+        assert_eq!(
+            messages("build:\n\tif true; then\n\t    make\n\tfi\n"),
+            ["3:1 `build` mixes tabs and spaces in its indentation"]
+        );
+    }
+
+    #[test]
+    fn should_find_pushd_without_popd() {
+        assert_eq!(
+            messages(
+                "g/:\n    pushd g\n    a:\n        make\nh/:\n    pushd h\n    b:\n        make\n    popd\n"
+            ),
+            ["2:5 `pushd` in the group `g/` has no matching `popd`"]
+        );
+    }
+}
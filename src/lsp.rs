@@ -0,0 +1,542 @@
+use std::collections::{HashMap, HashSet};
+use std::path::Path;
+use std::{fs, io};
+
+use lsp_server::{Connection, Message, Notification, Request, RequestId, Response};
+use lsp_types::notification::{
+    DidChangeTextDocument, DidCloseTextDocument, DidOpenTextDocument, Notification as _,
+    PublishDiagnostics,
+};
+use lsp_types::request::{Completion, GotoDefinition, HoverRequest, Request as _};
+use lsp_types::{
+    CompletionItem, CompletionItemKind, CompletionOptions, CompletionParams, CompletionResponse,
+    Diagnostic, DiagnosticSeverity, GotoDefinitionParams, GotoDefinitionResponse, Hover,
+    HoverContents, HoverParams, HoverProviderCapability, MarkupContent, MarkupKind, OneOf,
+    Position, PublishDiagnosticsParams, Range, ServerCapabilities, TextDocumentSyncCapability,
+    TextDocumentSyncKind, Uri,
+};
+
+use crate::cli;
+use crate::error::{MkError, highlight_width};
+use crate::graph;
+use crate::include;
+use crate::node::Node;
+use crate::source::{Location, SourceFile, Sources};
+use crate::syntax;
+
+/// Texts of the open documents by name
+type Documents = HashMap<String, String>;
+
+/// Decoded path of a `file` uri, other documents are named by their uri
+fn document_name(uri: &Uri) -> String {
+    let path = uri.path().as_estr().decode().into_string();
+    match (uri.scheme(), path) {
+        (Some(scheme), Ok(path)) if scheme.as_str().eq_ignore_ascii_case("file") => {
+            path.into_owned()
+        }
+        _ => uri.as_str().to_string(),
+    }
+}
+
+/// Uri of a document name, paths are percent-encoded into `file` uris
+fn document_uri(name: &str) -> Option<Uri> {
+    if !name.starts_with('/') {
+        return name.parse().ok();
+    }
+    let mut uri = "file://".to_string();
+    for byte in name.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' | b'/' => {
+                uri.push(byte as char)
+            }
+            byte => uri.push_str(&format!("%{byte:02X}")),
+        }
+    }
+    uri.parse().ok()
+}
+
+/// Sources of the document with the input files next to it like `mk` reads them there,
+/// open documents are read from the editor and others from the disk
+fn read_sources(documents: &Documents, name: &str) -> Sources {
+    let read = |name: &str| match documents.get(name) {
+        Some(text) => Ok(text.clone()),
+        None => fs::read_to_string(name),
+    };
+    let directory = Path::new(name).parent().and_then(Path::to_str);
+    let inputs = match directory {
+        Some(directory) if Path::new(name).is_absolute() => {
+            let pattern = format!(
+                "{}/{}",
+                glob::Pattern::escape(directory),
+                cli::DEFAULT_INPUT
+            );
+            cli::input_files(&pattern).unwrap_or_default()
+        }
+        _ => Vec::new(),
+    };
+    // a document which is not an input is read alone
+    if inputs.iter().any(|input| input == name)
+        && let Ok(sources) = cli::read_sources(inputs, read)
+    {
+        return sources;
+    }
+    let mut sources = Sources::new(vec![SourceFile {
+        name: name.to_string(),
+        content: documents.get(name).cloned().unwrap_or_default(),
+    }]);
+    include::read(&mut sources, read);
+    sources
+}
+
+/// Sources of the document and its nodes, or the error of desugaring
+fn analyze<T>(
+    documents: &Documents,
+    name: &str,
+    f: impl FnOnce(&Sources, Result<&[Node], MkError>) -> T,
+) -> T {
+    let sources = read_sources(documents, name);
+    let content = match syntax::desugar(&sources, &Default::default()) {
+        Ok(content) => content,
+        Err(error) => return f(&sources, Err(error)),
+    };
+    let nodes = syntax::nodes(&content, &sources);
+    f(&sources, Ok(&nodes))
+}
+
+/// Range of the location with the columns counted in UTF-16 code units
+fn range(sources: &Sources, location: Location, width: Option<usize>) -> Range {
+    let line = location.line.saturating_sub(1) as u32;
+    let text = sources.line(location.file, location.line).unwrap_or("");
+    let column = location.column.saturating_sub(1).min(text.len());
+    let (before, after) = match text.is_char_boundary(column) {
+        true => text.split_at(column),
+        false => (text, ""),
+    };
+    let start = before.encode_utf16().count();
+    let width: usize = after
+        .chars()
+        .take(highlight_width(after, 0, width))
+        .map(char::len_utf16)
+        .sum();
+    Range::new(
+        Position::new(line, start as u32),
+        Position::new(line, (start + width) as u32),
+    )
+}
+
+fn contains(range: &Range, position: Position) -> bool {
+    range.start <= position && position < range.end
+}
+
+/// Names of the dependencies written at the position,
+/// a glob pattern names every segment it was expanded to
+fn dependencies_at<'a>(nodes: &[Node<'a>], sources: &Sources, position: Position) -> Vec<&'a str> {
+    let mut names = Vec::new();
+    for node in nodes {
+        let Node::Segment { dependencies, .. } = node else {
+            continue;
+        };
+        for dep in dependencies {
+            if contains(&range(sources, dep.location, None), position) && !names.contains(&dep.name)
+            {
+                names.push(dep.name);
+            }
+        }
+    }
+    names
+}
+
+/// Name of the segment whose header name is at the position
+fn segment_at<'a>(nodes: &[Node<'a>], sources: &Sources, position: Position) -> Option<&'a str> {
+    nodes.iter().find_map(|node| match node {
+        Node::Segment { name, location, .. }
+            if contains(&range(sources, *location, None), position) =>
+        {
+            Some(*name)
+        }
+        _ => None,
+    })
+}
+
+fn definitions(documents: &Documents, name: &str, position: Position) -> Vec<lsp_types::Location> {
+    analyze(documents, name, |sources, nodes| {
+        let Ok(nodes) = nodes else {
+            return Vec::new();
+        };
+        let names = dependencies_at(nodes, sources, position);
+        nodes
+            .iter()
+            .filter_map(|node| match node {
+                // segments of the other files are in their own documents
+                Node::Segment { name, location, .. } if names.contains(name) => {
+                    Some(lsp_types::Location::new(
+                        document_uri(location.file)?,
+                        range(sources, *location, None),
+                    ))
+                }
+                _ => None,
+            })
+            .collect()
+    })
+}
+
+fn hover(documents: &Documents, name: &str, position: Position) -> Option<Hover> {
+    analyze(documents, name, |sources, nodes| {
+        let nodes = nodes.ok()?;
+        let name = dependencies_at(nodes, sources, position)
+            .first()
+            .copied()
+            .or_else(|| segment_at(nodes, sources, position))?;
+        let mut value = format!("**{name}**");
+        let description = nodes
+            .iter()
+            .filter(|node| matches!(node, Node::Segment { name: n, .. } if *n == name))
+            .find_map(|node| node.description());
+        for line in description.into_iter().flatten() {
+            value.push_str("\n\n");
+            value.push_str(line.trim());
+        }
+        Some(Hover {
+            contents: HoverContents::Markup(MarkupContent {
+                kind: MarkupKind::Markdown,
+                value,
+            }),
+            range: None,
+        })
+    })
+}
+
+/// Segment names for the dependency list of the header at the position,
+/// inside a group names of the group are relative and others start with `/`
+fn completions(documents: &Documents, name: &str, position: Position) -> Vec<CompletionItem> {
+    let text = documents.get(name).map(String::as_str).unwrap_or("");
+    let line = text.lines().nth(position.line as usize).unwrap_or("");
+    // the character of the position counts UTF-16 code units
+    let mut units = 0;
+    let before: String = line
+        .chars()
+        .take_while(|c| {
+            units += c.len_utf16();
+            units <= position.character as usize
+        })
+        .collect();
+    if !before.contains(':') {
+        return Vec::new();
+    }
+    analyze(documents, name, |sources, nodes| {
+        let Ok(nodes) = nodes else {
+            return Vec::new();
+        };
+        let group = nodes
+            .iter()
+            .find(|node| {
+                matches!(node, Node::Segment { location, .. }
+                    if location.line == position.line as usize + 1)
+            })
+            .and_then(|node| node.group(sources));
+        let mut seen = HashSet::new();
+        nodes
+            .iter()
+            .filter_map(|node| match node {
+                // groups of the segment are its implicit dependencies
+                Node::Segment { name, .. }
+                    if group.is_some_and(|g| g == *name || graph::is_parent_group(name, g)) =>
+                {
+                    None
+                }
+                Node::Segment { name, .. } if seen.insert(*name) => {
+                    let label = match group {
+                        None => name.to_string(),
+                        Some(group) => match name.strip_prefix(group) {
+                            Some(rest) if rest.starts_with('/') => rest[1..].to_string(),
+                            _ => format!("/{name}"),
+                        },
+                    };
+                    Some(CompletionItem {
+                        label,
+                        kind: Some(CompletionItemKind::FUNCTION),
+                        detail: node.description().map(|d| d.join("\n").trim().to_string()),
+                        ..Default::default()
+                    })
+                }
+                _ => None,
+            })
+            .collect()
+    })
+}
+
+/// Diagnostics of the error located in the document
+fn diagnostic(sources: &Sources, name: &str, error: &MkError) -> Vec<Diagnostic> {
+    error
+        .locations()
+        .into_iter()
+        .filter(|(location, _)| location.file == name)
+        .map(|(location, width)| Diagnostic {
+            range: range(sources, location, width),
+            severity: Some(DiagnosticSeverity::ERROR),
+            source: Some("mk".to_string()),
+            message: error.to_string(),
+            ..Default::default()
+        })
+        .collect()
+}
+
+/// Desugaring errors, undefined dependencies and cycles reachable from any segment
+fn diagnostics(documents: &Documents, name: &str) -> Vec<Diagnostic> {
+    analyze(documents, name, |sources, nodes| match nodes {
+        Ok(nodes) => graph::problems(nodes)
+            .iter()
+            .flat_map(|error| diagnostic(sources, name, error))
+            .collect(),
+        Err(error) => diagnostic(sources, name, &error),
+    })
+}
+
+fn respond<T: serde::Serialize>(
+    connection: &Connection,
+    id: RequestId,
+    result: T,
+) -> io::Result<()> {
+    connection
+        .sender
+        .send(Message::Response(Response::new_ok(id, result)))
+        .map_err(io::Error::other)
+}
+
+fn publish(connection: &Connection, documents: &Documents, uri: Uri) -> io::Result<()> {
+    let diagnostics = diagnostics(documents, &document_name(&uri));
+    let params = PublishDiagnosticsParams::new(uri, diagnostics, None);
+    connection
+        .sender
+        .send(Message::Notification(Notification::new(
+            PublishDiagnostics::METHOD.to_string(),
+            params,
+        )))
+        .map_err(io::Error::other)
+}
+
+fn handle_request(
+    connection: &Connection,
+    documents: &Documents,
+    request: Request,
+) -> io::Result<()> {
+    match request.method.as_str() {
+        GotoDefinition::METHOD => {
+            let (id, params) = request
+                .extract::<GotoDefinitionParams>(GotoDefinition::METHOD)
+                .map_err(io::Error::other)?;
+            let position = params.text_document_position_params;
+            let uri = position.text_document.uri;
+            let locations = definitions(documents, &document_name(&uri), position.position);
+            respond(connection, id, GotoDefinitionResponse::Array(locations))
+        }
+        HoverRequest::METHOD => {
+            let (id, params) = request
+                .extract::<HoverParams>(HoverRequest::METHOD)
+                .map_err(io::Error::other)?;
+            let position = params.text_document_position_params;
+            let uri = position.text_document.uri;
+            let hover = hover(documents, &document_name(&uri), position.position);
+            respond(connection, id, hover)
+        }
+        Completion::METHOD => {
+            let (id, params) = request
+                .extract::<CompletionParams>(Completion::METHOD)
+                .map_err(io::Error::other)?;
+            let position = params.text_document_position;
+            let uri = position.text_document.uri;
+            let items = completions(documents, &document_name(&uri), position.position);
+            respond(connection, id, CompletionResponse::Array(items))
+        }
+        _ => {
+            let response = Response::new_err(
+                request.id,
+                lsp_server::ErrorCode::MethodNotFound as i32,
+                format!("unsupported request {}", request.method),
+            );
+            connection
+                .sender
+                .send(Message::Response(response))
+                .map_err(io::Error::other)
+        }
+    }
+}
+
+fn handle_notification(
+    connection: &Connection,
+    documents: &mut Documents,
+    notification: Notification,
+) -> io::Result<()> {
+    match notification.method.as_str() {
+        DidOpenTextDocument::METHOD => {
+            let params: lsp_types::DidOpenTextDocumentParams =
+                serde_json::from_value(notification.params)?;
+            let document = params.text_document;
+            documents.insert(document_name(&document.uri), document.text);
+            publish(connection, documents, document.uri)?;
+        }
+        DidChangeTextDocument::METHOD => {
+            let params: lsp_types::DidChangeTextDocumentParams =
+                serde_json::from_value(notification.params)?;
+            // full synchronization, the last change is the whole text
+            if let Some(change) = params.content_changes.into_iter().last() {
+                let uri = params.text_document.uri;
+                documents.insert(document_name(&uri), change.text);
+                publish(connection, documents, uri)?;
+            }
+        }
+        DidCloseTextDocument::METHOD => {
+            let params: lsp_types::DidCloseTextDocumentParams =
+                serde_json::from_value(notification.params)?;
+            documents.remove(&document_name(&params.text_document.uri));
+        }
+        _ => {}
+    }
+    Ok(())
+}
+
+/// Serve the language server protocol over stdio until the client exits
+pub fn run<'a>() -> Result<(), MkError<'a>> {
+    let (connection, io_threads) = Connection::stdio();
+    let capabilities = ServerCapabilities {
+        text_document_sync: Some(TextDocumentSyncCapability::Kind(TextDocumentSyncKind::FULL)),
+        definition_provider: Some(OneOf::Left(true)),
+        hover_provider: Some(HoverProviderCapability::Simple(true)),
+        completion_provider: Some(CompletionOptions {
+            trigger_characters: Some(vec![" ".to_string(), "/".to_string()]),
+            ..Default::default()
+        }),
+        ..Default::default()
+    };
+    connection
+        .initialize(serde_json::to_value(capabilities).map_err(io::Error::other)?)
+        .map_err(io::Error::other)?;
+    let mut documents = HashMap::new();
+    for message in &connection.receiver {
+        match message {
+            Message::Request(request) => {
+                if connection
+                    .handle_shutdown(&request)
+                    .map_err(io::Error::other)?
+                {
+                    break;
+                }
+                handle_request(&connection, &documents, request)?;
+            }
+            Message::Notification(notification) => {
+                handle_notification(&connection, &mut documents, notification)?;
+            }
+            Message::Response(_) => {}
+        }
+    }
+    drop(connection);
+    io_threads.join()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::str::FromStr;
+
+    const TEXT: &str = "g/:\n    # builds a\n    a: /x b\n    b:\nx: g/*\n    make\n";
+
+    fn open(text: &str) -> (Documents, String) {
+        let name = document_name(&Uri::from_str("file:///mkfile").unwrap());
+        (HashMap::from([(name.clone(), text.to_string())]), name)
+    }
+
+    #[test]
+    fn should_find_definitions_of_dependencies() {
+        let (documents, name) = open(TEXT);
+        let lines = |position| {
+            definitions(&documents, &name, position)
+                .iter()
+                .map(|l| l.range.start.line)
+                .collect::<Vec<_>>()
+        };
+        // rooted and group-relative dependencies of `g/a`
+        assert_eq!(lines(Position::new(2, 8)), vec![4]);
+        assert_eq!(lines(Position::new(2, 10)), vec![3]);
+        // glob pattern of `x`
+        assert_eq!(lines(Position::new(4, 4)), vec![2, 3]);
+        assert_eq!(lines(Position::new(4, 0)), Vec::<u32>::new());
+    }
+
+    #[test]
+    fn should_complete_relative_names_in_group() {
+        let (documents, name) = open(TEXT);
+        let labels: Vec<_> = completions(&documents, &name, Position::new(2, 10))
+            .into_iter()
+            .map(|c| c.label)
+            .collect();
+        assert_eq!(labels, vec!["a", "b", "/x"]);
+    }
+
+    #[test]
+    fn should_report_undefined_dependencies_and_cycles() {
+        let (documents, name) = open("a: b\nb: a\nc: d\n");
+        let messages: Vec<_> = diagnostics(&documents, &name)
+            .into_iter()
+            .map(|d| (d.range.start.line, d.message))
+            .collect();
+        assert_eq!(
+            messages,
+            vec![
+                (0, "dependency cycle: a -> b -> a".to_string()),
+                (1, "dependency cycle: a -> b -> a".to_string()),
+                (2, "`c` depends on undefined segment `d`".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn should_count_columns_in_utf16() {
+        let (documents, name) = open("a: é𝐀 b\n");
+        let ranges: Vec<_> = diagnostics(&documents, &name)
+            .into_iter()
+            .map(|d| (d.range.start.character, d.range.end.character))
+            .collect();
+        assert_eq!(ranges, vec![(3, 6), (7, 8)]);
+        // the colon follows the two code units of `𝐀`
+        let (documents, name) = open("𝐀: \nb:\n");
+        let labels = |character| {
+            completions(&documents, &name, Position::new(0, character))
+                .into_iter()
+                .map(|c| c.label)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(labels(2), Vec::<String>::new());
+        assert_eq!(labels(3), vec!["𝐀", "b"]);
+    }
+
+    #[test]
+    fn should_read_input_files_next_to_document() {
+        let directory = std::env::temp_dir().join(format!("mk lsp {}", std::process::id()));
+        fs::create_dir_all(&directory).unwrap();
+        fs::write(directory.join("mkfile.local"), "# builds b\nb:\n").unwrap();
+        let uri = Uri::from_str(&format!(
+            "file://{}/mkfile",
+            directory.to_str().unwrap().replace(' ', "%20")
+        ))
+        .unwrap();
+        let name = document_name(&uri);
+        let documents = HashMap::from([(name.clone(), "a: b\n".to_string())]);
+        fs::write(&name, "").unwrap();
+        let found = (
+            diagnostics(&documents, &name),
+            definitions(&documents, &name, Position::new(0, 3)),
+        );
+        fs::remove_dir_all(&directory).unwrap();
+        assert_eq!(found.0, Vec::new());
+        let sibling = uri.as_str().replace("/mkfile", "/mkfile.local");
+        assert_eq!(
+            found
+                .1
+                .iter()
+                .map(|l| (l.uri.as_str(), l.range.start.line))
+                .collect::<Vec<_>>(),
+            vec![(sibling.as_str(), 1)]
+        );
+    }
+}
@@ -1,47 +1,190 @@
-use std::io::{IsTerminal, Read};
+use std::io::IsTerminal;
+use std::process::ExitCode;
 
+mod arguments;
 mod chars;
 mod cli;
 mod dependencies_collector;
+mod error;
+mod executor;
+mod explain;
+mod export;
+mod formatter;
 mod glob_pattern;
 mod graph;
 mod groups;
+mod include;
+mod lint;
+mod lsp;
 mod node;
+mod parameters;
+mod picker;
 mod printer;
+mod script;
 mod segments_scanner;
+mod source;
+mod suggestions;
 mod syntax;
+mod variables;
 
+use clap::ArgMatches;
+use error::MkError;
 use printer::Printer;
+use segments_scanner::SegmentsScanner;
+use source::{MappedText, Sources};
 
-fn main() -> Result<(), Box<dyn std::error::Error>> {
-    let Some(matches) = cli::get_matches()? else {
-        return Ok(());
-    };
-    let content = {
-        let mut stdin = std::io::stdin();
-        if stdin.is_terminal() {
-            cli::read_content_from_files(matches.get_one::<String>("input").unwrap())?
+fn printer(matches: &ArgMatches) -> &Printer {
+    matches
+        .get_one::<Printer>("printer")
+        .unwrap_or(if std::io::stdout().is_terminal() {
+            &Printer::Executor
         } else {
-            let mut input = String::new();
-            stdin.read_to_string(&mut input)?;
-            input
-        }
-    };
-    let content = syntax::desugar(content.as_str());
+            &Printer::Stdout
+        })
+}
+
+fn graph_options(matches: &ArgMatches) -> graph::Options {
+    graph::Options {
+        strict: if matches.get_flag("strict") {
+            true
+        } else if matches.get_flag("lenient") {
+            false
+        } else {
+            printer(matches) == &Printer::Executor
+        },
+        order: *matches.get_one::<graph::Order>("order").unwrap(),
+        abbreviations: matches.get_flag("abbreviations"),
+        depth: if matches.get_flag("no-deps") {
+            Some(0)
+        } else {
+            matches.get_one("depth").copied()
+        },
+        only_dependencies: matches.get_flag("only-deps"),
+        explain: matches.get_flag("explain"),
+    }
+}
 
-    let printer =
-        matches
-            .get_one::<Printer>("printer")
-            .unwrap_or(if std::io::stdout().is_terminal() {
-                &Printer::Executor
-            } else {
-                &Printer::Stdout
-            });
+fn run<'a>(
+    matches: &'a ArgMatches,
+    targets: &[&'a str],
+    sources: &'a Sources,
+    content: &'a MappedText,
+    environment: Vec<(String, String)>,
+) -> Result<(), MkError<'a>> {
     let args: Vec<&String> = matches.get_many("arguments").unwrap_or_default().collect();
-    let targets: Vec<_> = matches
+    let executor = executor::Options {
+        jobs: matches.get_one("jobs").copied(),
+        delivery: *matches.get_one("script").unwrap(),
+        shell: matches.get_one::<String>("shell").unwrap().clone(),
+        environment,
+    };
+    printer(matches).print(
+        targets,
+        content,
+        sources,
+        &graph_options(matches),
+        &executor,
+        args,
+    )
+}
+
+fn report(error: &MkError, sources: Option<&Sources>) -> ExitCode {
+    eprint!("{}", error.render(sources));
+    ExitCode::from(error.exit_code())
+}
+
+fn main() -> ExitCode {
+    let Some(matches) = cli::get_matches() else {
+        return ExitCode::SUCCESS;
+    };
+    if matches.get_flag("lsp") {
+        return match lsp::run() {
+            Ok(()) => ExitCode::SUCCESS,
+            Err(error) => report(&error, None),
+        };
+    }
+    let in_place = std::io::stdin().is_terminal();
+    let sources = if in_place {
+        cli::read_sources_from_files(matches.get_one::<String>("input").unwrap())
+    } else {
+        cli::read_sources_from_stdin().map_err(MkError::from)
+    };
+    let sources = match sources {
+        Ok(sources) => sources,
+        Err(error) => return report(&error, None),
+    };
+    if matches.get_flag("format") {
+        let style = formatter::Style {
+            sort_dependencies: matches.get_flag("sort-dependencies"),
+        };
+        return match formatter::run(&sources, &style, matches.get_flag("check"), in_place) {
+            Ok(()) => ExitCode::SUCCESS,
+            Err(error) => report(&error, Some(&sources)),
+        };
+    }
+    let variables: Vec<(String, String)> = matches
+        .get_many("define")
+        .unwrap_or_default()
+        .cloned()
+        .collect();
+    let values: Vec<&str> = matches
         .get_many::<String>("target")
         .unwrap_or_default()
         .map(|s| s.as_str())
         .collect();
-    printer.print(targets.as_slice(), content.as_str(), args)
+    let targets = match arguments::split(&values) {
+        Ok(targets) => targets,
+        Err(error) => return report(&error, None),
+    };
+    let names: Vec<&str> = targets.iter().map(|t| t.name).collect();
+    let options = syntax::Options {
+        variables: &variables,
+        targets: &names,
+    };
+    let content = match syntax::desugar(&sources, &options) {
+        Ok(content) => content,
+        Err(error) => return report(&error, Some(&sources)),
+    };
+    if matches.get_flag("lint") {
+        return match lint::run(&content, &sources, matches.get_flag("deny")) {
+            Ok(()) => ExitCode::SUCCESS,
+            Err(error) => report(&error, Some(&sources)),
+        };
+    }
+    let defaults: Vec<String> = if targets.is_empty() && printer(&matches).runs_default() {
+        let nodes: Vec<_> = SegmentsScanner::new(&content.content).collect();
+        let configured = matches.get_one::<String>("default").map(|s| s.as_str());
+        let mut defaults = graph::default_targets(&nodes, configured);
+        // a terminal user without a default picks the targets
+        if defaults.is_empty() && in_place {
+            defaults = match picker::pick(&nodes) {
+                Ok(picked) => picked,
+                Err(error) => return report(&error, Some(&sources)),
+            };
+        }
+        defaults.into_iter().map(str::to_string).collect()
+    } else {
+        Vec::new()
+    };
+    let targets: Vec<_> = if targets.is_empty() {
+        defaults
+            .iter()
+            .map(|name| arguments::Target {
+                name,
+                arguments: Vec::new(),
+            })
+            .collect()
+    } else {
+        targets
+    };
+    let names: Vec<&str> = targets.iter().map(|t| t.name).collect();
+    let (content, environment) =
+        match arguments::apply(content, &sources, &targets, &graph_options(&matches)) {
+            Ok(applied) => applied,
+            Err(error) => return report(&error, Some(&sources)),
+        };
+    match run(&matches, &names, &sources, &content, environment) {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(error) => report(&error, Some(&sources)),
+    }
 }
@@ -1,19 +1,135 @@
+use super::source::{Location, Locator, Sources, Span};
+use super::variables;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Dependency<'a> {
+    pub name: &'a str,
+    pub span: Span,
+    pub location: Location<'a>,
+    /// Glob pattern of the header the dependency was expanded from
+    pub pattern: Option<&'a str>,
+}
+
+impl<'a> Dependency<'a> {
+    pub fn new(name: &'a str, span: Span) -> Self {
+        Self {
+            name,
+            span,
+            location: Location::default(),
+            pattern: None,
+        }
+    }
+}
+
+// Description line marking the segment run when no target is given
+const DEFAULT_MARK: &str = "@default";
+
+/// Description line annotating the segment rather than describing it
+pub fn is_annotation(line: &str) -> bool {
+    line.trim() == DEFAULT_MARK || Parameter::parse(line).is_some()
+}
+
+/// Named argument of a target declared by a `# @param NAME[=DEFAULT] text` line
+/// of its description, a parameter without a default is required
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Parameter<'a> {
+    pub name: &'a str,
+    pub default: Option<&'a str>,
+    pub description: &'a str,
+}
+
+impl<'a> Parameter<'a> {
+    /// Parameter declared by a description line
+    pub fn parse(line: &'a str) -> Option<Self> {
+        let rest = line.trim_start().strip_prefix("@param")?;
+        if !rest.starts_with(char::is_whitespace) {
+            return None;
+        }
+        let rest = rest.trim_start();
+        let (declaration, description) = rest.split_once(char::is_whitespace).unwrap_or((rest, ""));
+        let (name, default) = match declaration.split_once('=') {
+            Some((name, default)) => (name, Some(default)),
+            None => (declaration, None),
+        };
+        variables::is_name(name).then(|| Self {
+            name,
+            default,
+            description: description.trim(),
+        })
+    }
+}
+
 #[derive(Debug, PartialEq)]
 pub enum Node<'a> {
-    Content(&'a str),
+    Content {
+        content: &'a str,
+        span: Span,
+        location: Location<'a>,
+    },
     Segment {
         name: &'a str,
         content: &'a str,
         indentation: &'a str,
-        dependencies: Vec<&'a str>,
+        dependencies: Vec<Dependency<'a>>,
+        span: Span,
+        location: Location<'a>,
     },
 }
 
 impl<'a> Node<'a> {
+    /// Replace positions in the scanned text with locations in the sources
+    pub fn locate(&mut self, locator: &Locator<'a, 'a>) {
+        match self {
+            Node::Content { span, location, .. } => *location = locator.locate(span.start),
+            Node::Segment {
+                span,
+                location,
+                dependencies,
+                ..
+            } => {
+                *location = locator.locate(span.start);
+                for dep in dependencies {
+                    (dep.location, dep.pattern) = locator.locate_dependency(dep.span.start);
+                }
+            }
+        }
+    }
+
+    /// Group the segment was defined in, taken from the name written in its header
+    pub fn group(&self, sources: &Sources) -> Option<&'a str> {
+        let Node::Segment { name, location, .. } = self else {
+            return None;
+        };
+        let header = sources.line(location.file, location.line)?;
+        let written = header.split(':').next()?.trim().trim_end_matches('/');
+        name.strip_suffix(written)?
+            .strip_suffix('/')
+            .filter(|group| !group.is_empty())
+    }
+
+    /// Interpreter named by a `#!` line starting the segment content
+    pub fn interpreter(&self) -> Option<&'a str> {
+        match self {
+            Node::Content { .. } => None,
+            Node::Segment { content, .. } => content
+                .lines()
+                .next()?
+                .trim_start()
+                .strip_prefix("#!")
+                .map(str::trim)
+                .filter(|interpreter| !interpreter.is_empty()),
+        }
+    }
+
     pub fn description(&self) -> Option<Vec<&'a str>> {
         match self {
-            Node::Content(_) => None,
+            Node::Content { .. } => None,
             Node::Segment { content, .. } => {
+                // the interpreter annotation is not a part of the description
+                let content = match self.interpreter() {
+                    Some(_) => content.split_once('\n').map_or("", |(_, rest)| rest),
+                    None => content,
+                };
                 if content.is_empty() {
                     return None;
                 }
@@ -43,4 +159,54 @@ impl<'a> Node<'a> {
             }
         }
     }
+
+    /// Parameters declared in the description
+    pub fn parameters(&self) -> Vec<Parameter<'a>> {
+        self.description()
+            .unwrap_or_default()
+            .into_iter()
+            .filter_map(Parameter::parse)
+            .collect()
+    }
+
+    /// Marked by a `# @default` line of the description
+    pub fn is_default(&self) -> bool {
+        self.description()
+            .unwrap_or_default()
+            .iter()
+            .any(|line| line.trim() == DEFAULT_MARK)
+    }
+}
+
+/// Nodes without positions for the tests of the modules working on scanned nodes
+#[cfg(test)]
+pub mod fixtures {
+    use super::*;
+
+    pub fn content(content: &str) -> Node<'_> {
+        Node::Content {
+            content,
+            span: Span::default(),
+            location: Location::default(),
+        }
+    }
+
+    pub fn segment<'a>(
+        name: &'a str,
+        content: &'a str,
+        indentation: &'a str,
+        dependencies: &[&'a str],
+    ) -> Node<'a> {
+        Node::Segment {
+            name,
+            content,
+            indentation,
+            dependencies: dependencies
+                .iter()
+                .map(|d| Dependency::new(d, Span::default()))
+                .collect(),
+            span: Span::default(),
+            location: Location::default(),
+        }
+    }
 }
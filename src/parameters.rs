@@ -0,0 +1,216 @@
+use std::collections::HashMap;
+
+use super::chars::is_valid_segment_name_char;
+use super::node::Node;
+use super::segments_scanner::SegmentsScanner;
+use super::source::{MappedText, Origin, line_column, line_starts, lines_count, span_map};
+use super::variables;
+
+// Calls nested deeper are not instantiated, so that a template
+// calling itself with growing arguments ends as an undefined dependency
+const MAX_DEPTH: usize = 64;
+
+/// Base name and arguments of a `name(a,b)` segment or dependency
+pub fn split_call(name: &str) -> Option<(&str, Vec<&str>)> {
+    let (base, args) = name.strip_suffix(')')?.split_once('(')?;
+    if base.is_empty() || base.ends_with('/') || args.contains(['(', ')']) {
+        return None;
+    }
+    if args.is_empty() {
+        return Some((base, Vec::new()));
+    }
+    Some((base, args.split(',').collect()))
+}
+
+/// Length of the `name(a,b)` call the text starts with
+pub fn call_len(text: &str) -> Option<usize> {
+    let len = text.find(')')? + 1;
+    let (base, args) = split_call(&text[..len])?;
+    let valid = base.chars().all(is_valid_segment_name_char)
+        && args.iter().all(|arg| !arg.contains(char::is_whitespace));
+    valid.then_some(len)
+}
+
+/// Whether the name can be written in a header, a call is the only name with parentheses
+pub fn is_segment_name(name: &str) -> bool {
+    name.chars().next().is_some_and(char::is_alphabetic)
+        && (name.chars().all(is_valid_segment_name_char) || call_len(name) == Some(name.len()))
+}
+
+/// Base name and parameters of a parameterized segment
+pub fn template(name: &str) -> Option<(&str, Vec<&str>)> {
+    split_call(name).filter(|(_, params)| params.iter().all(|p| variables::is_name(p)))
+}
+
+type Values<'a> = HashMap<&'a str, String>;
+
+/// Name of the template the call instantiates with the values of its parameters
+fn instantiate<'a>(templates: &[&'a str], call: &str) -> Option<(&'a str, Values<'a>)> {
+    let (base, args) = split_call(call)?;
+    templates.iter().find_map(|name| {
+        let (b, params) = template(name)?;
+        if b != base || params.len() != args.len() {
+            return None;
+        }
+        let values = params.into_iter().zip(args.iter().map(|a| a.to_string()));
+        Some((*name, values.collect()))
+    })
+}
+
+/// Parameterized segments replaced by their instantiations, one for each
+/// distinct call in the dependencies or the `targets`, with `{{param}}`
+/// substituted in the dependencies and the content.
+/// Lines of an instantiation keep the origins of the template lines.
+pub fn desugar(text: MappedText, targets: &[&str]) -> MappedText {
+    let nodes: Vec<_> = SegmentsScanner::new(&text.content).collect();
+    let mut templates = Vec::new();
+    let mut calls: Vec<(String, usize)> = targets.iter().map(|t| (t.to_string(), 0)).collect();
+    for node in &nodes {
+        let Node::Segment {
+            name, dependencies, ..
+        } = node
+        else {
+            continue;
+        };
+        if template(name).is_some() {
+            if !templates.contains(name) {
+                templates.push(*name);
+            }
+        } else {
+            calls.extend(dependencies.iter().map(|d| (d.name.to_string(), 0)));
+        }
+    }
+    if templates.is_empty() {
+        return text;
+    }
+    let mut instances: Vec<(String, &str, Values)> = Vec::new();
+    let mut i = 0;
+    while let Some((call, depth)) = calls.get(i).cloned() {
+        i += 1;
+        if depth > MAX_DEPTH || instances.iter().any(|(name, ..)| *name == call) {
+            continue;
+        }
+        let Some((template, values)) = instantiate(&templates, &call) else {
+            continue;
+        };
+        // dependencies of the instantiation may call other templates
+        for node in &nodes {
+            if let Node::Segment {
+                name, dependencies, ..
+            } = node
+                && *name == template
+            {
+                calls.extend(
+                    dependencies
+                        .iter()
+                        .map(|d| (variables::substitute(d.name, &values), depth + 1)),
+                );
+            }
+        }
+        instances.push((call, template, values));
+    }
+    let starts = line_starts(&text.content);
+    let mut result = MappedText::default();
+    for node in &nodes {
+        match node {
+            Node::Segment {
+                name,
+                content,
+                dependencies,
+                span,
+                ..
+            } if templates.contains(name) => {
+                let map = span_map(&starts, &text.map, *span);
+                let header = map.get(0);
+                let origins: Vec<_> = dependencies
+                    .iter()
+                    .map(|dep| {
+                        let (line, column) = line_column(&starts, dep.span.start);
+                        text.map.locate(Origin { line, column })
+                    })
+                    .collect();
+                for (call, _, values) in instances.iter().filter(|(_, t, _)| t == name) {
+                    let names: Vec<_> = dependencies
+                        .iter()
+                        .map(|dep| variables::substitute(dep.name, values))
+                        .collect();
+                    let deps: Vec<_> = names
+                        .iter()
+                        .zip(&origins)
+                        .map(|(name, origin)| (name.as_str(), *origin, None))
+                        .collect();
+                    result.push_header(call, &deps, header);
+                    result.push_lines("\n", header);
+                    result.push(
+                        &variables::substitute(content, values),
+                        &map.slice(1, lines_count(content)),
+                    );
+                }
+            }
+            Node::Segment { span, .. } | Node::Content { span, .. } => result.push(
+                &text.content[span.start..span.end],
+                &span_map(&starts, &text.map, *span),
+            ),
+        }
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn desugar_text(content: &str, targets: &[&str]) -> String {
+        desugar(MappedText::new(content), targets).content
+    }
+
+    #[test]
+    fn should_split_call() {
+        assert_eq!(
+            split_call("deploy(prod,eu)"),
+            Some(("deploy", vec!["prod", "eu"]))
+        );
+        assert_eq!(split_call("g/f()"), Some(("g/f", vec![])));
+        assert_eq!(split_call("deploy"), None);
+        assert_eq!(split_call("(x)"), None);
+        assert_eq!(template("deploy(prod.eu)"), None);
+    }
+
+    #[test]
+    fn should_take_only_calls_for_names_with_parentheses() {
+        assert!(is_segment_name("deploy(env,region)"));
+        assert!(is_segment_name("g/build"));
+        assert!(!is_segment_name("deploy(env)x"));
+        assert!(!is_segment_name("a,b"));
+        assert!(!is_segment_name("f(a b)"));
+        assert_eq!(call_len("f(a): b"), Some(4));
+    }
+
+    #[test]
+    fn should_instantiate_called_templates() {
+        assert_eq!(
+            desugar_text(
+                "deploy(env): build\n    ./deploy {{env}}\nbuild:\n    make\nrelease: deploy(prod) deploy(staging)\n",
+                &[]
+            ),
+            "deploy(prod): build\n    ./deploy prod\ndeploy(staging): build\n    ./deploy staging\nbuild:\n    make\nrelease: deploy(prod) deploy(staging)\n"
+        );
+    }
+
+    #[test]
+    fn should_instantiate_nested_calls_and_targets() {
+        assert_eq!(
+            desugar_text(
+                "push(env,tag): image({{tag}})\n    push {{tag}} {{env}}\nimage(tag):\n    build {{tag}}\n",
+                &["push(prod,v1)"]
+            ),
+            "push(prod,v1): image(v1)\n    push v1 prod\nimage(v1):\n    build v1\n"
+        );
+    }
+
+    #[test]
+    fn should_stop_at_growing_recursion() {
+        let content = desugar_text("a(x): a({{x}}x)\n    echo\nb: a(x)\n", &[]);
+        assert_eq!(content.matches("\n    echo").count(), MAX_DEPTH + 1);
+    }
+}
@@ -0,0 +1,417 @@
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::os::fd::AsRawFd;
+
+use super::error::MkError;
+use super::graph;
+use super::node::{self, Node};
+
+const TTY: &str = "/dev/tty";
+// Rows of the prompt and the key help
+const CHROME_ROWS: usize = 2;
+const DEFAULT_SIZE: (usize, usize) = (24, 80);
+
+/// Segment offered by the picker
+#[derive(Debug, PartialEq)]
+pub struct Candidate<'a> {
+    pub name: &'a str,
+    pub description: String,
+    pub dependencies: Vec<&'a str>,
+}
+
+/// Segments in the order of definition with the description
+/// and the dependencies of their first definition
+pub fn candidates<'a>(nodes: &[Node<'a>]) -> Vec<Candidate<'a>> {
+    let mut candidates: Vec<Candidate> = Vec::new();
+    for node in nodes {
+        let Node::Segment {
+            name, dependencies, ..
+        } = node
+        else {
+            continue;
+        };
+        if candidates.iter().any(|c| c.name == *name) {
+            continue;
+        }
+        let description: Vec<_> = node
+            .description()
+            .unwrap_or_default()
+            .into_iter()
+            .filter(|line| !node::is_annotation(line))
+            .map(str::trim)
+            .collect();
+        candidates.push(Candidate {
+            name,
+            description: description.join(" "),
+            // groups are implied by the name
+            dependencies: dependencies
+                .iter()
+                .map(|d| d.name)
+                .filter(|d| !graph::is_parent_group(d, name))
+                .collect(),
+        });
+    }
+    candidates
+}
+
+/// Score of the query matched as a case-insensitive subsequence of the text,
+/// consecutive characters and the starts of words and path components score higher
+fn score(query: &str, text: &str) -> Option<usize> {
+    let mut score = 0;
+    let mut previous: Option<char> = None;
+    let mut consecutive = false;
+    let mut query = query.chars().flat_map(char::to_lowercase).peekable();
+    for c in text.chars() {
+        let Some(q) = query.peek() else {
+            break;
+        };
+        if c.to_lowercase().eq(std::iter::once(*q)) {
+            query.next();
+            score += 1;
+            if consecutive {
+                score += 2;
+            }
+            if previous.is_none_or(|p| matches!(p, '/' | '-' | '_' | ' ' | '.')) {
+                score += 3;
+            }
+            consecutive = true;
+        } else {
+            consecutive = false;
+        }
+        previous = Some(c);
+    }
+    query.peek().is_none().then_some(score)
+}
+
+/// Indices of the candidates matching the query, best first.
+/// Names are preferred to descriptions, ties keep the order of definition.
+pub fn filter(candidates: &[Candidate], query: &str) -> Vec<usize> {
+    let mut matches: Vec<(usize, (bool, usize))> = candidates
+        .iter()
+        .enumerate()
+        .filter_map(|(i, c)| {
+            let rank = match score(query, c.name) {
+                Some(score) => (true, score),
+                None => (false, score(query, &c.description)?),
+            };
+            Some((i, rank))
+        })
+        .collect();
+    matches.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+    matches.into_iter().map(|(i, _)| i).collect()
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Key {
+    Char(char),
+    Backspace,
+    Up,
+    Down,
+    /// Select or deselect the candidate under the cursor
+    Toggle,
+    Accept,
+    Cancel,
+}
+
+/// Keys of the bytes read from the terminal, unknown sequences are skipped
+pub fn keys(bytes: &[u8]) -> Vec<Key> {
+    let text = String::from_utf8_lossy(bytes);
+    let mut chars = text.chars();
+    let mut keys = Vec::new();
+    while let Some(c) = chars.next() {
+        let key = match c {
+            '\x1b' => match chars.next() {
+                None => Key::Cancel,
+                Some('[' | 'O') => {
+                    // parameters of a control sequence end with its final character
+                    match chars.by_ref().find(|c| ('@'..='~').contains(c)) {
+                        Some('A') => Key::Up,
+                        Some('B') => Key::Down,
+                        _ => continue,
+                    }
+                }
+                // Alt with a key
+                _ => continue,
+            },
+            '\x03' | '\x04' => Key::Cancel,
+            '\r' | '\n' => Key::Accept,
+            '\t' => Key::Toggle,
+            '\x7f' | '\x08' => Key::Backspace,
+            '\x10' => Key::Up,
+            '\x0e' => Key::Down,
+            c if c.is_control() => continue,
+            c => Key::Char(c),
+        };
+        keys.push(key);
+    }
+    keys
+}
+
+/// Query, cursor and selection of the candidates
+pub struct Picker<'c, 'a> {
+    candidates: &'c [Candidate<'a>],
+    query: String,
+    matches: Vec<usize>,
+    cursor: usize,
+    selected: Vec<bool>,
+}
+
+impl<'c, 'a> Picker<'c, 'a> {
+    pub fn new(candidates: &'c [Candidate<'a>]) -> Self {
+        Self {
+            candidates,
+            query: String::new(),
+            matches: (0..candidates.len()).collect(),
+            cursor: 0,
+            selected: vec![false; candidates.len()],
+        }
+    }
+
+    fn search(&mut self) {
+        self.matches = filter(self.candidates, &self.query);
+        self.cursor = 0;
+    }
+
+    /// Names of the picked candidates once accepted: the selected ones in
+    /// the order of definition, or the one under the cursor if none is selected.
+    /// Cancelling picks nothing.
+    pub fn key(&mut self, key: Key) -> Option<Vec<&'a str>> {
+        match key {
+            Key::Char(c) => {
+                self.query.push(c);
+                self.search();
+            }
+            Key::Backspace => {
+                if self.query.pop().is_some() {
+                    self.search();
+                }
+            }
+            Key::Up => self.cursor = self.cursor.saturating_sub(1),
+            Key::Down => {
+                if self.cursor + 1 < self.matches.len() {
+                    self.cursor += 1;
+                }
+            }
+            Key::Toggle => {
+                if let Some(i) = self.matches.get(self.cursor) {
+                    self.selected[*i] = !self.selected[*i];
+                    return self.key(Key::Down);
+                }
+            }
+            Key::Accept => {
+                let selected: Vec<_> = self
+                    .candidates
+                    .iter()
+                    .zip(&self.selected)
+                    .filter(|(_, selected)| **selected)
+                    .map(|(c, _)| c.name)
+                    .collect();
+                if !selected.is_empty() {
+                    return Some(selected);
+                }
+                return self
+                    .matches
+                    .get(self.cursor)
+                    .map(|i| vec![self.candidates[*i].name]);
+            }
+            Key::Cancel => return Some(Vec::new()),
+        }
+        None
+    }
+
+    /// Screen of the picker, the list scrolled to keep the cursor visible
+    fn render(&self, (rows, columns): (usize, usize)) -> String {
+        let height = rows.saturating_sub(CHROME_ROWS).max(1);
+        let first = self.cursor.saturating_sub(height - 1);
+        let selected = self.selected.iter().filter(|s| **s).count();
+        let mut lines = vec![format!(
+            "> {}  ({}/{}, {selected} selected)",
+            self.query,
+            self.matches.len(),
+            self.candidates.len()
+        )];
+        let width = self
+            .candidates
+            .iter()
+            .map(|c| c.name.chars().count())
+            .max()
+            .unwrap_or(0);
+        for (row, i) in self.matches.iter().enumerate().skip(first).take(height) {
+            let candidate = &self.candidates[*i];
+            let mut line = format!(
+                "{}{} {:width$}  {}",
+                if row == self.cursor { '>' } else { ' ' },
+                if self.selected[*i] { '*' } else { ' ' },
+                candidate.name,
+                candidate.description,
+            );
+            if !candidate.dependencies.is_empty() {
+                line.push_str(&format!("  <- {}", candidate.dependencies.join(" ")));
+            }
+            lines.push(line);
+        }
+        lines.resize(height + 1, String::new());
+        lines.push("tab: select  enter: run  esc: cancel".to_string());
+        let mut screen = String::from("\x1b[H\x1b[2J");
+        let lines: Vec<String> = lines
+            .iter()
+            .map(|line| line.trim_end().chars().take(columns).collect())
+            .collect();
+        screen.push_str(&lines.join("\r\n"));
+        // the cursor is left at the end of the query
+        screen.push_str(&format!("\x1b[1;{}H", self.query.chars().count() + 3));
+        screen
+    }
+}
+
+/// Raw mode of the terminal restored on drop
+struct RawMode {
+    tty: File,
+    saved: libc::termios,
+}
+
+impl RawMode {
+    fn enable(tty: File) -> io::Result<Self> {
+        let fd = tty.as_raw_fd();
+        let mut saved = unsafe { std::mem::zeroed::<libc::termios>() };
+        if unsafe { libc::tcgetattr(fd, &mut saved) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let mut raw = saved;
+        // keys are read one by one, Ctrl-C included
+        raw.c_lflag &= !(libc::ICANON | libc::ECHO | libc::ISIG);
+        raw.c_iflag &= !(libc::ICRNL | libc::IXON);
+        raw.c_cc[libc::VMIN] = 1;
+        raw.c_cc[libc::VTIME] = 0;
+        if unsafe { libc::tcsetattr(fd, libc::TCSANOW, &raw) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(Self { tty, saved })
+    }
+
+    fn size(&self) -> (usize, usize) {
+        let mut size = unsafe { std::mem::zeroed::<libc::winsize>() };
+        let result = unsafe { libc::ioctl(self.tty.as_raw_fd(), libc::TIOCGWINSZ, &mut size) };
+        if result < 0 || size.ws_row == 0 || size.ws_col == 0 {
+            return DEFAULT_SIZE;
+        }
+        (size.ws_row as usize, size.ws_col as usize)
+    }
+}
+
+impl Drop for RawMode {
+    fn drop(&mut self) {
+        unsafe { libc::tcsetattr(self.tty.as_raw_fd(), libc::TCSANOW, &self.saved) };
+    }
+}
+
+fn tty_error(source: io::Error) -> MkError<'static> {
+    MkError::Io {
+        path: Some(TTY.to_string()),
+        source,
+    }
+}
+
+/// Targets picked in the terminal from the segments,
+/// drawn on the alternate screen so that the output of the run is left clean
+pub fn pick<'a>(nodes: &[Node<'a>]) -> Result<Vec<&'a str>, MkError<'a>> {
+    let candidates = candidates(nodes);
+    if candidates.is_empty() {
+        return Ok(Vec::new());
+    }
+    let tty = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open(TTY)
+        .map_err(tty_error)?;
+    let mut raw = RawMode::enable(tty).map_err(tty_error)?;
+    let mut picker = Picker::new(&candidates);
+    let picked = (|| {
+        raw.tty.write_all(b"\x1b[?1049h")?;
+        let mut buffer = [0; 64];
+        loop {
+            let screen = picker.render(raw.size());
+            raw.tty.write_all(screen.as_bytes())?;
+            raw.tty.flush()?;
+            let read = raw.tty.read(&mut buffer)?;
+            if read == 0 {
+                return Ok(Vec::new());
+            }
+            for key in keys(&buffer[..read]) {
+                if let Some(picked) = picker.key(key) {
+                    return Ok(picked);
+                }
+            }
+        }
+    })();
+    let restored = raw.tty.write_all(b"\x1b[?1049l");
+    let picked = picked.and_then(|picked| restored.map(|_| picked));
+    match picked.map_err(tty_error)? {
+        picked if picked.is_empty() => Err(MkError::SelectionCancelled),
+        picked => Ok(picked),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn candidate<'a>(name: &'a str, description: &str) -> Candidate<'a> {
+        Candidate {
+            name,
+            description: description.to_string(),
+            dependencies: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn should_prefer_word_starts_and_names() {
+        let candidates = [
+            candidate("app/ppp/preview", ""),
+            candidate("app/ppp/build", ""),
+            candidate("lint", "checks the build"),
+            candidate("bundle", ""),
+        ];
+        assert_eq!(filter(&candidates, "build"), [1, 2]);
+        assert_eq!(filter(&candidates, "apb"), [1]);
+        assert_eq!(filter(&candidates, "B"), [1, 3, 2]);
+        assert_eq!(score("xyz", "app"), None);
+    }
+
+    #[test]
+    fn should_parse_keys() {
+        assert_eq!(
+            keys(b"a\x1b[A\x1b[B\t\x7f\r"),
+            [
+                Key::Char('a'),
+                Key::Up,
+                Key::Down,
+                Key::Toggle,
+                Key::Backspace,
+                Key::Accept
+            ]
+        );
+        assert_eq!(keys(b"\x1b"), [Key::Cancel]);
+        assert_eq!(keys(b"\x1b[5~\x1bOB\x03"), [Key::Down, Key::Cancel]);
+    }
+
+    #[test]
+    fn should_pick_selected_or_current_candidate() {
+        let candidates = [
+            candidate("build", ""),
+            candidate("test", ""),
+            candidate("deploy", ""),
+        ];
+        let mut picker = Picker::new(&candidates);
+        for key in [Key::Down, Key::Down, Key::Toggle, Key::Up, Key::Up] {
+            assert_eq!(picker.key(key), None);
+        }
+        assert_eq!(picker.key(Key::Toggle), None);
+        assert_eq!(picker.key(Key::Accept), Some(vec!["build", "deploy"]));
+        let mut picker = Picker::new(&candidates);
+        picker.key(Key::Char('t'));
+        picker.key(Key::Char('s'));
+        assert_eq!(picker.key(Key::Accept), Some(vec!["test"]));
+        assert_eq!(picker.key(Key::Cancel), Some(vec![]));
+    }
+}
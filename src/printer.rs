@@ -1,14 +1,15 @@
-use std::{
-    env, error::Error, ffi::OsStr, io::Write, os::unix::fs::PermissionsExt, path::Path,
-    process::Command,
-};
+use std::ffi::OsStr;
 
 use clap::ValueEnum;
-use rand::{Rng, distr::Alphanumeric};
 
+use crate::error::MkError;
+use crate::executor;
+use crate::explain;
+use crate::export;
 use crate::graph;
-use crate::node::Node;
-use crate::segments_scanner::SegmentsScanner;
+use crate::node::{self, Node};
+use crate::source::{MappedText, Sources};
+use crate::syntax;
 
 #[derive(Clone, ValueEnum, PartialEq)]
 pub enum Printer {
@@ -16,32 +17,69 @@ pub enum Printer {
     Executor,
     Targets,
     DesugarDebug,
-}
-
-fn target_not_found(target: &str) -> Box<dyn Error> {
-    format!("target not found: {}", target).into()
+    /// Graphviz DOT graph of the resolved segments
+    GraphDot,
+    /// Mermaid flowchart of the resolved segments
+    GraphMermaid,
+    /// JSON adjacency lists of the resolved segments
+    GraphJson,
+    /// JSON list of the targets, all of them if none are requested
+    TargetsJson,
 }
 
 impl Printer {
-    pub fn print<I, S>(
+    /// Runs the default targets when none are given, the listings list all of them
+    pub fn runs_default(&self) -> bool {
+        !matches!(self, Self::Targets | Self::TargetsJson | Self::DesugarDebug)
+    }
+
+    pub fn print<'a, I, S>(
         &self,
-        targets: &[&str],
-        content: &str,
+        targets: &[&'a str],
+        content: &'a MappedText,
+        sources: &'a Sources,
+        options: &graph::Options,
+        executor: &executor::Options,
         args: I,
-    ) -> Result<(), Box<dyn Error>>
+    ) -> Result<(), MkError<'a>>
     where
         I: IntoIterator<Item = S>,
         S: AsRef<OsStr>,
     {
-        let nodes: Vec<_> = SegmentsScanner::new(content).collect();
+        let nodes = syntax::nodes(content, sources);
+        let resolve = || {
+            let resolved = graph::resolve_segments(&nodes, targets, options)?;
+            for warning in &resolved.warnings {
+                eprint!("{}", warning.render_warning(Some(sources)));
+            }
+            if options.explain {
+                eprint!(
+                    "{}",
+                    explain::explain(&nodes, &resolved.targets, &resolved.segments)
+                );
+            }
+            Ok::<_, MkError<'a>>(resolved.segments)
+        };
+        let all = || {
+            nodes
+                .iter()
+                .filter_map(|node| match node {
+                    Node::Segment { name, .. } => Some(*name),
+                    _ => None,
+                })
+                .collect()
+        };
         match self {
             Self::DesugarDebug => {
-                println!("{}", content);
+                println!("{}", content.content);
                 Ok(())
             }
             Self::Targets => {
-                let segments =
-                    graph::resolve_segments(&nodes, targets).map_err(target_not_found)?;
+                let segments = if targets.is_empty() {
+                    all()
+                } else {
+                    resolve()?
+                };
                 for node in nodes {
                     if let Node::Segment { name, .. } = node {
                         if !segments.contains(name) {
@@ -50,38 +88,64 @@ impl Printer {
                         println!("{}", name);
                         if let Some(desc) = node.description() {
                             for line in desc {
-                                println!(" {}", line);
+                                if !node::is_annotation(line) {
+                                    println!(" {}", line);
+                                }
+                            }
+                        }
+                        for parameter in node.parameters() {
+                            let declaration = match parameter.default {
+                                Some(default) => format!("{}={default}", parameter.name),
+                                None => format!("{} (required)", parameter.name),
+                            };
+                            match parameter.description {
+                                "" => println!("  {declaration}"),
+                                description => println!("  {declaration}  {description}"),
                             }
                         }
                     }
                 }
                 Ok(())
             }
+            Self::TargetsJson => {
+                let segments = if targets.is_empty() {
+                    all()
+                } else {
+                    resolve()?
+                };
+                print!("{}", export::targets_json(&nodes, &segments, sources));
+                Ok(())
+            }
+            Self::GraphDot | Self::GraphMermaid | Self::GraphJson => {
+                let subgraph = graph::subgraph(&nodes, &resolve()?, sources);
+                let export = match self {
+                    Self::GraphDot => export::dot,
+                    Self::GraphMermaid => export::mermaid,
+                    _ => export::json,
+                };
+                print!("{}", export(&subgraph));
+                Ok(())
+            }
             Self::Stdout => {
-                let resolved = graph::resolve(&nodes, targets).map_err(target_not_found)?;
-                print!("{}", resolved);
+                print!("{}", graph::render(&nodes, &resolve()?, options.order));
                 Ok(())
             }
             Self::Executor => {
-                let resolved = graph::resolve(&nodes, targets).map_err(target_not_found)?;
-                let prefix: String = rand::rng()
-                    .sample_iter(&Alphanumeric)
-                    .take(5)
-                    .map(char::from)
-                    .collect();
-                let path = Path::join(&env::temp_dir(), format!("mk-{}.tmp", prefix));
-                let file_path = path.to_str().unwrap().to_string();
-                {
-                    let mut file = std::fs::File::create(&path)?;
-                    let mut permissions = file.metadata()?.permissions();
-                    permissions.set_mode(0o755);
-                    file.set_permissions(permissions)?;
-                    file.write_all(resolved.as_bytes())?;
-                    file.flush()?;
+                let segments = resolve()?;
+                let args: Vec<S> = args.into_iter().collect();
+                match executor.jobs {
+                    Some(limit) => executor::run_jobs(
+                        &executor::plan(&nodes, &segments),
+                        limit,
+                        executor,
+                        &args,
+                    ),
+                    None => executor::run_script(
+                        &graph::render(&nodes, &segments, options.order),
+                        executor,
+                        &args,
+                    ),
                 }
-                Command::new(file_path).args(args).spawn()?.wait()?;
-                std::fs::remove_file(path)?;
-                Ok(())
             }
         }
     }
@@ -0,0 +1,258 @@
+use std::ffi::{CString, OsStr};
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+use std::os::unix::fs::OpenOptionsExt;
+use std::os::unix::process::CommandExt;
+use std::path::PathBuf;
+use std::process::{Child, Command, Stdio};
+use std::{env, thread};
+
+use clap::ValueEnum;
+use rand::{Rng, distr::Alphanumeric};
+
+use crate::error::MkError;
+
+// Attempts to find a free name for a script file
+const CREATE_ATTEMPTS: usize = 16;
+
+/// How the script is passed to its interpreter
+#[derive(Debug, Clone, Copy, Default, PartialEq, ValueEnum)]
+pub enum Delivery {
+    /// Executable file in the temporary directory only mk can write
+    #[default]
+    File,
+    /// Anonymous file in memory, not affected by a noexec temporary directory
+    Memory,
+    /// Standard input of the interpreter named in the `#!` line or of the shell
+    Stdin,
+}
+
+/// Interpreter and its optional argument from the `#!` line
+pub fn shebang(content: &str) -> Option<(&str, Option<&str>)> {
+    let line = content.lines().next()?.strip_prefix("#!")?.trim();
+    // like the kernel, everything after the interpreter is a single argument
+    let (interpreter, argument) = match line.split_once(char::is_whitespace) {
+        Some((interpreter, argument)) => (interpreter, Some(argument.trim())),
+        None => (line, None),
+    };
+    if interpreter.is_empty() {
+        return None;
+    }
+    Some((interpreter, argument.filter(|a| !a.is_empty())))
+}
+
+enum Storage {
+    File(PathBuf),
+    Memory(OwnedFd),
+    Stdin(String),
+}
+
+impl Storage {
+    fn path(&self) -> String {
+        match self {
+            Self::File(path) => path.display().to_string(),
+            Self::Memory(fd) => format!("/proc/self/fd/{}", fd.as_raw_fd()),
+            Self::Stdin(_) => "/dev/stdin".to_string(),
+        }
+    }
+}
+
+impl Drop for Storage {
+    fn drop(&mut self) {
+        if let Self::File(path) = self {
+            let _ = fs::remove_file(path);
+        }
+    }
+}
+
+/// Resolved content prepared for execution, cleaned up when dropped
+pub struct Script {
+    storage: Storage,
+    /// Command the script path is passed to, `None` to execute it directly
+    interpreter: Option<Vec<String>>,
+}
+
+fn io_error(path: String) -> impl Fn(io::Error) -> MkError<'static> {
+    move |source| MkError::Io {
+        path: Some(path.clone()),
+        source,
+    }
+}
+
+impl Script {
+    /// Scripts without a `#!` line are passed to `shell`
+    pub fn new(content: &str, delivery: Delivery, shell: &str) -> Result<Self, MkError<'static>> {
+        let interpreter = match shebang(content) {
+            Some(_) if delivery != Delivery::Stdin => None,
+            Some((interpreter, argument)) => Some(
+                std::iter::once(interpreter)
+                    .chain(argument)
+                    .map(str::to_string)
+                    .collect(),
+            ),
+            None => Some(shell.split_whitespace().map(str::to_string).collect()),
+        };
+        if interpreter.as_ref().is_some_and(Vec::is_empty) {
+            return Err(MkError::ExecutionFailure {
+                program: "script".to_string(),
+                source: io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "no #! line and no shell to interpret the script",
+                ),
+            });
+        }
+        let storage = match delivery {
+            Delivery::File => file(content)?,
+            Delivery::Memory => memory(content)?,
+            Delivery::Stdin => Storage::Stdin(content.to_string()),
+        };
+        Ok(Self {
+            storage,
+            interpreter,
+        })
+    }
+
+    /// Name of the executed program in error messages
+    pub fn program(&self) -> String {
+        match &self.interpreter {
+            Some(interpreter) => interpreter[0].clone(),
+            None => self.storage.path(),
+        }
+    }
+
+    pub fn spawn<S: AsRef<OsStr>>(
+        &self,
+        args: &[S],
+        environment: &[(String, String)],
+    ) -> Result<Child, MkError<'static>> {
+        let mut command = match &self.interpreter {
+            Some(interpreter) => {
+                let mut command = Command::new(&interpreter[0]);
+                command.args(&interpreter[1..]).arg(self.storage.path());
+                command
+            }
+            None => Command::new(self.storage.path()),
+        };
+        command
+            .args(args)
+            .envs(environment.iter().map(|(k, v)| (k, v)));
+        match &self.storage {
+            Storage::File(_) => {}
+            Storage::Memory(fd) => {
+                let fd = fd.as_raw_fd();
+                // the interpreter reopens the script by its path,
+                // so only the executed process keeps the descriptor
+                unsafe {
+                    command.pre_exec(move || {
+                        if libc::fcntl(fd, libc::F_SETFD, 0) < 0 {
+                            return Err(io::Error::last_os_error());
+                        }
+                        Ok(())
+                    });
+                }
+            }
+            Storage::Stdin(_) => {
+                command.stdin(Stdio::piped());
+            }
+        }
+        let mut child = command
+            .spawn()
+            .map_err(|source| MkError::ExecutionFailure {
+                program: self.program(),
+                source,
+            })?;
+        if let Storage::Stdin(content) = &self.storage {
+            let mut stdin = child.stdin.take().expect("stdin is piped");
+            let content = content.clone();
+            // the interpreter may start before reading the whole script
+            thread::spawn(move || stdin.write_all(content.as_bytes()));
+        }
+        Ok(child)
+    }
+}
+
+/// Exclusively created file, so that an existing file
+/// or a symlink planted in its place is never written
+fn file(content: &str) -> Result<Storage, MkError<'static>> {
+    let mut attempts = 0;
+    let (path, mut file) = loop {
+        let prefix: String = rand::rng()
+            .sample_iter(&Alphanumeric)
+            .take(5)
+            .map(char::from)
+            .collect();
+        let path = env::temp_dir().join(format!("mk-{}.tmp", prefix));
+        let created = OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .mode(0o700)
+            .open(&path);
+        match created {
+            Ok(file) => break (path, file),
+            Err(e) if e.kind() == io::ErrorKind::AlreadyExists => {
+                attempts += 1;
+                if attempts == CREATE_ATTEMPTS {
+                    return Err(io_error(path.display().to_string())(e));
+                }
+            }
+            Err(e) => return Err(io_error(path.display().to_string())(e)),
+        }
+    };
+    let storage = Storage::File(path);
+    file.write_all(content.as_bytes())
+        .and_then(|_| file.flush())
+        .map_err(io_error(storage.path()))?;
+    Ok(storage)
+}
+
+fn memory(content: &str) -> Result<Storage, MkError<'static>> {
+    let name = CString::new("mk").unwrap();
+    let fd = unsafe { libc::memfd_create(name.as_ptr(), libc::MFD_CLOEXEC) };
+    if fd < 0 {
+        return Err(io_error("memfd".to_string())(io::Error::last_os_error()));
+    }
+    let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+    let mut file = fs::File::from(fd.try_clone().map_err(MkError::from)?);
+    let storage = Storage::Memory(fd);
+    file.write_all(content.as_bytes())
+        .map_err(io_error(storage.path()))?;
+    Ok(storage)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn should_parse_shebang() {
+        assert_eq!(shebang("#!/bin/sh\necho"), Some(("/bin/sh", None)));
+        assert_eq!(
+            shebang("#! /usr/bin/env bash -xe\n"),
+            Some(("/usr/bin/env", Some("bash -xe")))
+        );
+        assert_eq!(shebang("#!\n"), None);
+        assert_eq!(shebang("echo\n"), None);
+    }
+
+    #[test]
+    fn should_pass_script_without_shebang_to_shell() {
+        let script = Script::new("echo\n", Delivery::Stdin, "bash -e").unwrap();
+        assert_eq!(
+            script.interpreter,
+            Some(vec!["bash".to_string(), "-e".to_string()])
+        );
+        let script = Script::new("#!/bin/sh\necho\n", Delivery::Stdin, "bash").unwrap();
+        assert_eq!(script.interpreter, Some(vec!["/bin/sh".to_string()]));
+        assert!(Script::new("echo\n", Delivery::Stdin, "").is_err());
+    }
+
+    #[test]
+    fn should_remove_script_file() {
+        let script = Script::new("#!/bin/sh\n", Delivery::File, "sh").unwrap();
+        let path = PathBuf::from(script.program());
+        assert!(path.exists());
+        drop(script);
+        assert!(!path.exists());
+    }
+}
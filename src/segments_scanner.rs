@@ -1,8 +1,11 @@
 use super::chars::*;
 use super::dependencies_collector::DependenciesCollector;
-use super::node::Node;
+use super::node::{Dependency, Node};
+use super::parameters::call_len;
+use super::source::{Location, Span};
 
 #[derive(Debug, PartialEq, Eq)]
+#[allow(clippy::enum_variant_names)]
 enum StateKind {
     SegmentNotDefined,
     SegmentStarts,
@@ -13,7 +16,8 @@ enum StateKind {
 struct ScannerState<'a> {
     kind: StateKind,
     segment: &'a str,
-    dependencies: Vec<&'a str>,
+    segment_start_position: usize,
+    dependencies: Vec<Dependency<'a>>,
     content_start_position: usize,
 }
 
@@ -35,12 +39,14 @@ impl<'a> SegmentsScanner<'a> {
                 ScannerState {
                     kind: StateKind::SegmentNotDefined,
                     segment: "",
+                    segment_start_position: 0,
                     dependencies: Vec::new(),
                     content_start_position: 0,
                 },
                 ScannerState {
                     kind: StateKind::SegmentNotDefined,
                     segment: "",
+                    segment_start_position: 0,
                     dependencies: Vec::new(),
                     content_start_position: 0,
                 },
@@ -71,14 +77,15 @@ impl<'a> SegmentsScanner<'a> {
         self.states[self.current_state_index] = state;
     }
 
-    fn dependencies(&mut self) -> Vec<&'a str> {
+    fn dependencies(&mut self) -> Vec<Dependency<'a>> {
         let (len, dependencies) =
-            DependenciesCollector::new(&self.content[self.cursor..]).collect();
+            DependenciesCollector::new(&self.content[self.cursor..], self.cursor).collect();
         self.cursor += len + 1;
         dependencies
     }
 
     fn start_segment(&mut self) -> bool {
+        let segment_start_position = self.cursor;
         let content = &self.content[self.cursor..];
         for (i, c) in content.char_indices() {
             if i == 0 && !c.is_alphabetic() {
@@ -92,13 +99,20 @@ impl<'a> SegmentsScanner<'a> {
                 self.cursor += i + 1;
                 return false;
             }
-            if c == ':' {
-                self.cursor += i + 1;
+            let name_end = match c {
+                ':' => Some(i),
+                // parentheses only enclose the arguments of a call
+                '(' => call_len(content).filter(|len| content[*len..].starts_with(':')),
+                _ => None,
+            };
+            if let Some(end) = name_end {
+                self.cursor += end + 1;
                 let dependencies = self.dependencies();
                 let content_start_position = self.cursor;
                 self.set_state(ScannerState {
                     kind: StateKind::SegmentStarts,
-                    segment: &content[..i],
+                    segment: &content[..end],
+                    segment_start_position,
                     dependencies,
                     content_start_position,
                 });
@@ -154,6 +168,7 @@ impl<'a> SegmentsScanner<'a> {
         self.set_state(ScannerState {
             kind: StateKind::SegmentNotDefined,
             segment: "",
+            segment_start_position: content_start_position,
             dependencies: Vec::new(),
             content_start_position,
         });
@@ -164,25 +179,29 @@ impl<'a> SegmentsScanner<'a> {
 mod tests {
     use super::*;
 
+    fn names<'a>(deps: &[Dependency<'a>]) -> Vec<&'a str> {
+        deps.iter().map(|d| d.name).collect()
+    }
+
     #[test]
     fn should_parse_simple_dependencies() {
         let mut scanner = SegmentsScanner::new("bar");
         let deps = scanner.dependencies();
-        assert_eq!(deps, vec!["bar"]);
+        assert_eq!(names(&deps), vec!["bar"]);
     }
 
     #[test]
     fn should_parse_multiple_dependencies() {
         let mut scanner = SegmentsScanner::new("foo  bar    baz");
         let deps = scanner.dependencies();
-        assert_eq!(deps, vec!["foo", "bar", "baz"]);
+        assert_eq!(names(&deps), vec!["foo", "bar", "baz"]);
     }
 
     #[test]
     fn should_parse_segments_till_newline() {
         let mut scanner = SegmentsScanner::new("foo\tbar\nbaz");
         let deps = scanner.dependencies();
-        assert_eq!(deps, vec!["foo", "bar"]);
+        assert_eq!(names(&deps), vec!["foo", "bar"]);
     }
 
     // TODO: Unicode tests
@@ -190,7 +209,7 @@ mod tests {
     #[test]
     fn should_start_simple_segment() {
         let mut scanner = SegmentsScanner::new("foo:");
-        assert_eq!(scanner.start_segment(), true);
+        assert!(scanner.start_segment());
         assert_eq!(scanner.state().kind, StateKind::SegmentStarts);
         assert_eq!(scanner.state().segment, "foo");
         assert_eq!(scanner.state().dependencies.len(), 0);
@@ -199,24 +218,35 @@ mod tests {
     #[test]
     fn should_start_segment_with_dependencies() {
         let mut scanner = SegmentsScanner::new("foo: bar\nbaz");
-        assert_eq!(scanner.start_segment(), true);
+        assert!(scanner.start_segment());
         assert_eq!(scanner.state().kind, StateKind::SegmentStarts);
         assert_eq!(scanner.state().segment, "foo");
         let deps = &scanner.state().dependencies;
-        assert!(deps.len() == 1 && deps.contains(&"bar"));
+        assert_eq!(names(deps), vec!["bar"]);
+        assert_eq!(deps[0].span, Span::new(5, 8));
+    }
+
+    #[test]
+    fn should_start_segment_of_call() {
+        let mut scanner = SegmentsScanner::new("deploy(env): build");
+        assert!(scanner.start_segment());
+        assert_eq!(scanner.state().segment, "deploy(env)");
+        assert_eq!(names(&scanner.state().dependencies), vec!["build"]);
+        assert!(!SegmentsScanner::new("a(b)c:").start_segment());
+        assert!(!SegmentsScanner::new("a,b:").start_segment());
     }
 
     #[test]
     fn should_detect_indentation() {
         let mut scanner = SegmentsScanner::new("  content");
-        assert_eq!(scanner.continue_segment(), true);
+        assert!(scanner.continue_segment());
         assert_eq!(scanner.segment_indentation, "  ");
     }
 
     #[test]
     fn should_ignore_whitespace_tail() {
         let mut scanner = SegmentsScanner::new("\t\t  \t\t");
-        assert_eq!(scanner.continue_segment(), false);
+        assert!(!scanner.continue_segment());
     }
 }
 
@@ -234,79 +264,106 @@ impl<'a> Iterator for SegmentsScanner<'a> {
                     if self.start_segment() {
                         let s = self.prev_state().content_start_position;
                         if initial_cursor - s > 0 {
-                            return Some(Node::Content(&self.content[s..initial_cursor]));
+                            return Some(self.common_content(s, initial_cursor));
                         }
                     }
                 }
                 // TODO: Merge with `SegmentContinued` and rename to `SegmentDefined`
                 StateKind::SegmentStarts => {
                     if !self.continue_segment() {
-                        let segment = Node::Segment {
-                            name: self.state().segment,
-                            dependencies: self.state().dependencies.clone(),
-                            indentation: "",
-                            content: "",
-                        };
+                        let content_start = self.state().content_start_position;
+                        let segment = self.segment(content_start, content_start, "");
                         self.finish_segment(initial_cursor);
                         return Some(segment);
                     }
                 }
                 StateKind::SegmentContinued => {
                     self.complete_segment();
-                    let segment_end = if &self.cursor > &self.content.len() {
-                        self.content.len()
-                    } else {
-                        self.cursor
-                    };
-                    let segment = Node::Segment {
-                        name: self.state().segment,
-                        dependencies: self.state().dependencies.clone(),
-                        indentation: self.segment_indentation,
-                        content: &self.content[self.state().content_start_position..segment_end],
-                    };
+                    let segment_end = self.cursor.min(self.content.len());
+                    let segment = self.segment(
+                        self.state().content_start_position,
+                        segment_end,
+                        self.segment_indentation,
+                    );
                     self.finish_segment(segment_end);
                     return Some(segment);
                 }
             }
             if self.done() {
+                let content_start = self.state().content_start_position;
                 if self.state().kind == StateKind::SegmentNotDefined {
-                    return Some(Node::Content(
-                        &self.content[self.state().content_start_position..],
-                    ));
+                    return Some(self.common_content(content_start, self.content.len()));
                 }
-                let content_start = self.state().content_start_position;
-                return Some(Node::Segment {
-                    name: self.state().segment,
-                    content: if content_start < self.content.len() {
-                        &self.content[self.state().content_start_position..]
-                    } else {
-                        ""
-                    },
-                    dependencies: self.state().dependencies.clone(),
-                    indentation: self.segment_indentation,
-                });
+                return Some(self.segment(
+                    content_start,
+                    self.content.len(),
+                    self.segment_indentation,
+                ));
             }
         }
     }
 }
 
+impl<'a> SegmentsScanner<'a> {
+    fn common_content(&self, start: usize, end: usize) -> Node<'a> {
+        Node::Content {
+            content: &self.content[start..end],
+            span: Span::new(start, end),
+            location: Location::default(),
+        }
+    }
+
+    fn segment(&self, content_start: usize, content_end: usize, indentation: &'a str) -> Node<'a> {
+        let state = self.state();
+        let content_start = content_start.min(self.content.len());
+        let content_end = content_end.max(content_start);
+        Node::Segment {
+            name: state.segment,
+            content: &self.content[content_start..content_end],
+            indentation,
+            dependencies: state.dependencies.clone(),
+            span: Span::new(state.segment_start_position, content_end),
+            location: Location::default(),
+        }
+    }
+}
+
 #[cfg(test)]
 mod iterator_tests {
     use super::*;
+    use crate::node::fixtures::content;
 
-    // For debug purposes
+    // For debug purposes, positions are checked separately
     fn collect<'a>(scanner: SegmentsScanner<'a>) -> Vec<Node<'a>> {
         let mut nodes = Vec::new();
-        for node in scanner {
+        for mut node in scanner {
+            match &mut node {
+                Node::Content { span, .. } => *span = Span::default(),
+                Node::Segment {
+                    span, dependencies, ..
+                } => {
+                    *span = Span::default();
+                    for dep in dependencies {
+                        dep.span = Span::default();
+                    }
+                }
+            }
             nodes.push(node);
         }
         nodes
     }
 
+    fn dependencies<'a>(names: &[&'a str]) -> Vec<Dependency<'a>> {
+        names
+            .iter()
+            .map(|name| Dependency::new(name, Span::default()))
+            .collect()
+    }
+
     #[test]
     fn should_emit_simple_content() {
         let scanner = SegmentsScanner::new("content");
-        assert!(collect(scanner) == vec![Node::Content("content")]);
+        assert!(collect(scanner) == vec![content("content")]);
     }
 
     #[test]
@@ -318,6 +375,8 @@ mod iterator_tests {
                     name: "foo",
                     content: "\tcontent",
                     dependencies: Vec::new(),
+                    span: Span::default(),
+                    location: Location::default(),
                     indentation: "\t",
                 }]
         );
@@ -326,14 +385,14 @@ mod iterator_tests {
     #[test]
     fn should_emit_empty_content() {
         let scanner = SegmentsScanner::new("");
-        assert!(collect(scanner) == vec![Node::Content("")]);
+        assert!(collect(scanner) == vec![content("")]);
     }
 
     #[test]
     fn should_emit_only_segment() {
         let scanner = SegmentsScanner::new("# NOTE: note\nexport DATA");
         let collected = collect(scanner);
-        assert!(collected == vec![Node::Content("# NOTE: note\nexport DATA")])
+        assert!(collected == vec![content("# NOTE: note\nexport DATA")])
     }
 
     #[test]
@@ -343,11 +402,13 @@ mod iterator_tests {
         assert!(
             collected
                 == vec![
-                    Node::Content("content\n"),
+                    content("content\n"),
                     Node::Segment {
                         name: "foo",
                         content: "\tcontent",
                         dependencies: Vec::new(),
+                        span: Span::default(),
+                        location: Location::default(),
                         indentation: "\t",
                     }
                 ]
@@ -360,14 +421,16 @@ mod iterator_tests {
         assert!(
             collect(scanner)
                 == vec![
-                    Node::Content("content\n"),
+                    content("content\n"),
                     Node::Segment {
                         name: "foo",
                         content: "\tfoo 1\n\tfoo 2\n",
                         dependencies: Vec::new(),
+                        span: Span::default(),
+                        location: Location::default(),
                         indentation: "\t",
                     },
-                    Node::Content("common")
+                    content("common")
                 ]
         );
     }
@@ -378,20 +441,24 @@ mod iterator_tests {
         assert!(
             collect(scanner)
                 == vec![
-                    Node::Content("common\n"),
+                    content("common\n"),
                     Node::Segment {
                         name: "foo",
                         content: "",
                         dependencies: Vec::new(),
+                        span: Span::default(),
+                        location: Location::default(),
                         indentation: "",
                     },
                     Node::Segment {
                         name: "bar",
                         content: "",
                         dependencies: Vec::new(),
+                        span: Span::default(),
+                        location: Location::default(),
                         indentation: "",
                     },
-                    Node::Content("baz")
+                    content("baz")
                 ]
         );
     }
@@ -403,20 +470,24 @@ mod iterator_tests {
         assert!(
             collect(scanner)
                 == vec![
-                    Node::Content("common\n"),
+                    content("common\n"),
                     Node::Segment {
                         name: "foo",
                         content: "\tfoo content\n",
                         dependencies: Vec::new(),
+                        span: Span::default(),
+                        location: Location::default(),
                         indentation: "\t",
                     },
                     Node::Segment {
                         name: "bar",
                         content: "\tbar content\n",
                         dependencies: Vec::new(),
+                        span: Span::default(),
+                        location: Location::default(),
                         indentation: "\t",
                     },
-                    Node::Content("common")
+                    content("common")
                 ]
         );
     }
@@ -437,20 +508,24 @@ popd",
         assert!(
             collect(scanner)
                 == vec![
-                    Node::Content("pushd folder\n\n"),
+                    content("pushd folder\n\n"),
                     Node::Segment {
                         name: "bar",
                         content: "    bar content\n    \n",
-                        dependencies: vec!["/foo"],
+                        dependencies: dependencies(&["/foo"]),
+                        span: Span::default(),
+                        location: Location::default(),
                         indentation: "    ",
                     },
                     Node::Segment {
                         name: "baz",
                         content: "    baz content\n    \n",
-                        dependencies: vec!["bar"],
+                        dependencies: dependencies(&["bar"]),
+                        span: Span::default(),
+                        location: Location::default(),
                         indentation: "    ",
                     },
-                    Node::Content("popd")
+                    content("popd")
                 ]
         )
     }
@@ -472,16 +547,20 @@ libs:
                         name: "artifacts",
                         content: "",
                         indentation: "",
-                        dependencies: vec!["dotnet",],
+                        dependencies: dependencies(&["dotnet"]),
+                        span: Span::default(),
+                        location: Location::default(),
                     },
-                    Node::Content("\n",),
+                    content("\n"),
                     Node::Segment {
                         name: "libs",
                         content: "  pushd packages/libs\n",
                         indentation: "  ",
-                        dependencies: vec![],
+                        dependencies: dependencies(&[]),
+                        span: Span::default(),
+                        location: Location::default(),
                     },
-                    Node::Content("",),
+                    content(""),
                 ]
         );
     }
@@ -504,14 +583,16 @@ popd",
         assert!(
             nodes
                 == vec![
-                    Node::Content("pushd probe\nsome line\n",),
+                    content("pushd probe\nsome line\n"),
                     Node::Segment {
                         name: "i",
                         content: "  bun install\n",
                         indentation: "  ",
-                        dependencies: vec![],
+                        dependencies: dependencies(&[]),
+                        span: Span::default(),
+                        location: Location::default(),
                     },
-                    Node::Content(
+                    content(
                         "mkdir -p public/doppio
 rsync -rL ../doppio/build/release/ public/doppio --delete\n"
                     ),
@@ -519,10 +600,29 @@ rsync -rL ../doppio/build/release/ public/doppio --delete\n"
                         name: "t",
                         content: "  another segment\n",
                         indentation: "  ",
-                        dependencies: vec![],
+                        dependencies: dependencies(&[]),
+                        span: Span::default(),
+                        location: Location::default(),
                     },
-                    Node::Content("bun run dev\npopd",),
+                    content("bun run dev\npopd"),
                 ]
         );
     }
+
+    #[test]
+    fn should_emit_spans() {
+        let nodes: Vec<_> = SegmentsScanner::new("common\nfoo: bar\n\tfoo content\n").collect();
+        let Node::Content { span, .. } = &nodes[0] else {
+            panic!("content expected");
+        };
+        assert_eq!(*span, Span::new(0, 7));
+        let Node::Segment {
+            span, dependencies, ..
+        } = &nodes[1]
+        else {
+            panic!("segment expected");
+        };
+        assert_eq!(*span, Span::new(7, 29));
+        assert_eq!(dependencies[0].span, Span::new(12, 15));
+    }
 }
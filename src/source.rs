@@ -0,0 +1,627 @@
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Span {
+    pub start: usize,
+    pub end: usize,
+}
+
+impl Span {
+    pub fn new(start: usize, end: usize) -> Self {
+        Self { start, end }
+    }
+
+    pub fn shift(self, offset: usize) -> Self {
+        Self {
+            start: self.start + offset,
+            end: self.end + offset,
+        }
+    }
+}
+
+/// One-based line and column in a source file
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Location<'a> {
+    pub file: &'a str,
+    pub line: usize,
+    pub column: usize,
+}
+
+impl std::fmt::Display for Location<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}:{}:{}", self.file, self.line, self.column)
+    }
+}
+
+/// Zero-based line of the input a desugared line was produced from
+/// and the number of leading bytes removed from it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Origin {
+    pub line: usize,
+    pub column: usize,
+}
+
+/// Part of a rewritten line copied from elsewhere, such as a dependency
+/// of a header line rebuilt by desugaring
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Piece {
+    /// Column the piece starts at in the rewritten line
+    pub start: usize,
+    pub origin: Origin,
+    /// Glob pattern the piece was expanded from
+    pub pattern: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Line {
+    origin: Origin,
+    pieces: Vec<Piece>,
+}
+
+impl From<Origin> for Line {
+    fn from(origin: Origin) -> Self {
+        Self {
+            origin,
+            pieces: Vec::new(),
+        }
+    }
+}
+
+static FIRST_LINE: Line = Line {
+    origin: Origin { line: 0, column: 0 },
+    pieces: Vec::new(),
+};
+
+/// Maps each line of a desugared text to its origin in the input text,
+/// the pieces of a rewritten line to their own origins
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct LineMap(Vec<Line>);
+
+impl LineMap {
+    pub fn identity(content: &str) -> Self {
+        Self(
+            (0..lines_count(content))
+                .map(|line| Origin { line, column: 0 }.into())
+                .collect(),
+        )
+    }
+
+    pub fn push(&mut self, origin: Origin) {
+        self.0.push(origin.into());
+    }
+
+    /// Lines past the end of the map belong to the last known origin
+    fn line(&self, line: usize) -> &Line {
+        self.0.get(line).or(self.0.last()).unwrap_or(&FIRST_LINE)
+    }
+
+    pub fn get(&self, line: usize) -> Origin {
+        self.line(line).origin
+    }
+
+    /// Piece of a rewritten line at the position
+    fn piece(&self, origin: Origin) -> Option<&Piece> {
+        self.line(origin.line)
+            .pieces
+            .iter()
+            .rev()
+            .find(|piece| piece.start <= origin.column)
+    }
+
+    /// Origin of a position in a piece
+    fn locate_piece(&self, origin: Origin) -> Option<Origin> {
+        self.piece(origin).map(|piece| Origin {
+            line: piece.origin.line,
+            column: piece.origin.column + origin.column - piece.start,
+        })
+    }
+
+    /// Glob pattern the text at the position was expanded from
+    pub fn pattern(&self, origin: Origin) -> Option<&str> {
+        self.piece(origin)?.pattern.as_deref()
+    }
+
+    /// Origin in the input of `self` of a position in its output
+    pub fn locate(&self, origin: Origin) -> Origin {
+        self.locate_piece(origin).unwrap_or_else(|| {
+            let outer = self.get(origin.line);
+            Origin {
+                line: outer.line,
+                column: outer.column + origin.column,
+            }
+        })
+    }
+
+    /// Line of `inner` rebased with `locate`, a line that is not rewritten
+    /// itself keeps the pieces of the line of `self` it comes from
+    fn compose_line(&self, inner: &Line, locate: fn(&Self, Origin) -> Origin) -> Line {
+        let pieces = if inner.pieces.is_empty() {
+            let shift = inner.origin.column;
+            let outer = &self.line(inner.origin.line).pieces;
+            let first = outer.iter().rposition(|piece| piece.start <= shift);
+            outer
+                .iter()
+                .enumerate()
+                .filter(|(i, piece)| piece.start >= shift || Some(*i) == first)
+                .map(|(_, piece)| Piece {
+                    start: piece.start.saturating_sub(shift),
+                    origin: Origin {
+                        line: piece.origin.line,
+                        column: piece.origin.column + shift.saturating_sub(piece.start),
+                    },
+                    pattern: piece.pattern.clone(),
+                })
+                .collect()
+        } else {
+            inner
+                .pieces
+                .iter()
+                .map(|piece| Piece {
+                    origin: locate(self, piece.origin),
+                    ..piece.clone()
+                })
+                .collect()
+        };
+        Line {
+            origin: locate(self, inner.origin),
+            pieces,
+        }
+    }
+
+    /// Rebase `inner` (which maps into the text described by `self`)
+    /// onto the input of `self`
+    pub fn compose(&self, inner: &LineMap) -> LineMap {
+        LineMap(
+            inner
+                .0
+                .iter()
+                .map(|line| self.compose_line(line, Self::locate))
+                .collect(),
+        )
+    }
+
+    /// Like `locate` for a map whose columns are the number of leading
+    /// bytes added to the lines instead of removed
+    pub fn locate_indented(&self, origin: Origin) -> Origin {
+        self.locate_piece(origin).unwrap_or_else(|| {
+            let outer = self.get(origin.line);
+            Origin {
+                line: outer.line,
+                column: origin.column.saturating_sub(outer.column),
+            }
+        })
+    }
+
+    /// Like `compose` for a map whose columns are the added bytes
+    pub fn compose_indented(&self, inner: &LineMap) -> LineMap {
+        LineMap(
+            inner
+                .0
+                .iter()
+                .map(|line| self.compose_line(line, Self::locate_indented))
+                .collect(),
+        )
+    }
+
+    /// Origins of `count` lines starting from `first`
+    pub fn slice(&self, first: usize, count: usize) -> LineMap {
+        LineMap(
+            (first..first + count)
+                .map(|line| self.line(line).clone())
+                .collect(),
+        )
+    }
+
+    /// Columns of lines with the removed indentation added back
+    pub fn restore_indentation(&mut self, len: usize) {
+        for line in &mut self.0 {
+            line.origin.column = line.origin.column.saturating_sub(len);
+            for piece in &mut line.pieces {
+                piece.start += len;
+            }
+        }
+    }
+
+    /// Map of `lines` consecutive lines starting from `origin`
+    pub fn lines(origin: Origin, lines: usize) -> Self {
+        Self(
+            (0..lines)
+                .map(|i| {
+                    Origin {
+                        line: origin.line + i,
+                        column: origin.column,
+                    }
+                    .into()
+                })
+                .collect(),
+        )
+    }
+}
+
+/// Text with the origin of each of its lines
+#[derive(Debug, Default, PartialEq)]
+pub struct MappedText {
+    pub content: String,
+    pub map: LineMap,
+}
+
+impl MappedText {
+    pub fn new(content: &str) -> Self {
+        Self {
+            content: content.to_string(),
+            map: LineMap::identity(content),
+        }
+    }
+
+    /// Append `text` whose lines originate from `map`
+    pub fn push(&mut self, text: &str, map: &LineMap) {
+        if text.is_empty() {
+            return;
+        }
+        for i in 0..lines_count(text) {
+            let line = map.line(i).clone();
+            if i > 0 || self.map.0.is_empty() {
+                self.map.0.push(line);
+            } else if self.content.is_empty() || self.content.ends_with('\n') {
+                // The first line of `text` starts a new line
+                if let Some(last) = self.map.0.last_mut() {
+                    *last = line;
+                }
+            }
+        }
+        self.content.push_str(text);
+    }
+
+    /// Append a `name: dependency...` header line originating from `origin`,
+    /// each dependency copied from its own origin, possibly expanded from a glob pattern
+    pub fn push_header(
+        &mut self,
+        name: &str,
+        dependencies: &[(&str, Origin, Option<&str>)],
+        origin: Origin,
+    ) {
+        let mut header = format!("{name}:");
+        let mut pieces = Vec::with_capacity(dependencies.len());
+        for (dependency, origin, pattern) in dependencies {
+            header.push(' ');
+            pieces.push(Piece {
+                start: header.len(),
+                origin: *origin,
+                pattern: pattern.map(str::to_string),
+            });
+            header.push_str(dependency);
+        }
+        self.push(&header, &LineMap(vec![Line { origin, pieces }]));
+    }
+
+    /// Append `text` whose lines originate from consecutive lines
+    pub fn push_lines(&mut self, text: &str, origin: Origin) {
+        self.push(text, &LineMap::lines(origin, lines_count(text)));
+    }
+
+    pub fn append(&mut self, other: MappedText) {
+        self.push(&other.content, &other.map);
+    }
+}
+
+pub fn lines_count(content: &str) -> usize {
+    content.bytes().filter(|b| *b == b'\n').count() + 1
+}
+
+/// Map of the lines in `span` of the text indexed by `line_starts`
+pub fn span_map(line_starts: &[usize], map: &LineMap, span: Span) -> LineMap {
+    let (first, _) = line_column(line_starts, span.start);
+    let (last, _) = line_column(line_starts, span.end);
+    map.slice(first, last - first + 1)
+}
+
+pub fn line_starts(content: &str) -> Vec<usize> {
+    std::iter::once(0)
+        .chain(
+            content
+                .bytes()
+                .enumerate()
+                .filter(|(_, b)| *b == b'\n')
+                .map(|(i, _)| i + 1),
+        )
+        .collect()
+}
+
+/// Zero-based line and column of the byte offset
+pub fn line_column(line_starts: &[usize], offset: usize) -> (usize, usize) {
+    let line = line_starts
+        .partition_point(|start| *start <= offset)
+        .saturating_sub(1);
+    (line, offset - line_starts[line])
+}
+
+pub struct SourceFile {
+    pub name: String,
+    pub content: String,
+}
+
+/// Input files concatenated in the reading order,
+/// followed by the files read for include directives
+pub struct Sources {
+    files: Vec<SourceFile>,
+    line_starts: Vec<Vec<usize>>,
+    content: String,
+    // First line of each file in the concatenated content
+    first_lines: Vec<usize>,
+    // Number of the input files
+    inputs: usize,
+    // Included files that could not be read with the reason
+    unreadable: Vec<(String, String)>,
+}
+
+impl Sources {
+    pub fn new(files: Vec<SourceFile>) -> Self {
+        let mut first_lines = Vec::with_capacity(files.len());
+        let mut line = 0;
+        for file in &files {
+            first_lines.push(line);
+            line += lines_count(&file.content);
+        }
+        let line_starts = files.iter().map(|f| line_starts(&f.content)).collect();
+        let content = files
+            .iter()
+            .map(|f| f.content.as_str())
+            .collect::<Vec<_>>()
+            .join("\n");
+        Self {
+            inputs: files.len(),
+            files,
+            line_starts,
+            content,
+            first_lines,
+            unreadable: Vec::new(),
+        }
+    }
+
+    /// Add an included file, its lines are numbered after the other files
+    /// but its content is not a part of the concatenated content
+    pub fn include(&mut self, file: SourceFile) {
+        let line = match (self.first_lines.last(), self.files.last()) {
+            (Some(first), Some(last)) => first + lines_count(&last.content),
+            _ => 0,
+        };
+        self.first_lines.push(line);
+        self.line_starts.push(line_starts(&file.content));
+        self.files.push(file);
+    }
+
+    pub fn mark_unreadable(&mut self, name: String, reason: String) {
+        self.unreadable.push((name, reason));
+    }
+
+    pub fn unreadable(&self, name: &str) -> Option<&str> {
+        self.unreadable
+            .iter()
+            .find(|(n, _)| n == name)
+            .map(|(_, reason)| reason.as_str())
+    }
+
+    pub fn inputs(&self) -> usize {
+        self.inputs
+    }
+
+    /// First line of the file in the concatenated content
+    pub fn first_line(&self, file: usize) -> usize {
+        self.first_lines[file]
+    }
+
+    pub fn content(&self) -> &str {
+        &self.content
+    }
+
+    pub fn files(&self) -> &[SourceFile] {
+        &self.files
+    }
+
+    /// Location in the original files of a zero-based line
+    /// and column of the concatenated content
+    pub fn locate(&self, line: usize, column: usize) -> Location<'_> {
+        let i = self
+            .first_lines
+            .partition_point(|first| *first <= line)
+            .saturating_sub(1);
+        Location {
+            file: self.files.get(i).map(|f| f.name.as_str()).unwrap_or(""),
+            line: line - self.first_lines.get(i).copied().unwrap_or(0) + 1,
+            column: column + 1,
+        }
+    }
+    /// Text of the one-based line of the file
+    pub fn line(&self, file: &str, line: usize) -> Option<&str> {
+        let i = self.files.iter().position(|f| f.name == file)?;
+        let content = &self.files[i].content;
+        let starts = &self.line_starts[i];
+        let start = *starts.get(line.checked_sub(1)?)?;
+        let end = starts.get(line).map(|s| s - 1).unwrap_or(content.len());
+        Some(content[start..end].trim_end_matches('\r'))
+    }
+}
+
+/// Resolves positions in a desugared text into locations in the sources
+pub struct Locator<'m, 'a> {
+    line_starts: Vec<usize>,
+    map: &'m LineMap,
+    sources: &'a Sources,
+}
+
+impl<'m, 'a> Locator<'m, 'a> {
+    pub fn new(content: &str, map: &'m LineMap, sources: &'a Sources) -> Self {
+        Self {
+            line_starts: line_starts(content),
+            map,
+            sources,
+        }
+    }
+
+    /// Line and column of the offset in the desugared text
+    fn position(&self, offset: usize) -> Origin {
+        let (line, column) = line_column(&self.line_starts, offset);
+        Origin { line, column }
+    }
+
+    pub fn locate(&self, offset: usize) -> Location<'a> {
+        let origin = self.map.locate(self.position(offset));
+        self.sources.locate(origin.line, origin.column)
+    }
+
+    /// Location of a dependency with the glob pattern it was expanded from
+    pub fn locate_dependency(&self, offset: usize) -> (Location<'a>, Option<&'m str>) {
+        (self.locate(offset), self.map.pattern(self.position(offset)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::syntax::fixtures;
+
+    fn sources() -> Sources {
+        Sources::new(vec![
+            SourceFile {
+                name: "a".to_string(),
+                content: "foo:\n\tfoo\n".to_string(),
+            },
+            SourceFile {
+                name: "b".to_string(),
+                content: "bar: foo".to_string(),
+            },
+        ])
+    }
+
+    #[test]
+    fn should_find_line_column() {
+        let starts = line_starts("foo\nbar");
+        assert_eq!(line_column(&starts, 0), (0, 0));
+        assert_eq!(line_column(&starts, 5), (1, 1));
+    }
+
+    #[test]
+    fn should_locate_line_in_files() {
+        let sources = sources();
+        assert_eq!(sources.content(), "foo:\n\tfoo\n\nbar: foo");
+        assert_eq!(
+            sources.locate(1, 1),
+            Location {
+                file: "a",
+                line: 2,
+                column: 2
+            }
+        );
+        assert_eq!(
+            sources.locate(3, 5),
+            Location {
+                file: "b",
+                line: 1,
+                column: 6
+            }
+        );
+    }
+
+    #[test]
+    fn should_compose_line_maps() {
+        let outer = LineMap(vec![
+            Origin { line: 0, column: 0 }.into(),
+            Origin { line: 2, column: 4 }.into(),
+        ]);
+        let inner = LineMap(vec![
+            Origin { line: 1, column: 2 }.into(),
+            Origin { line: 0, column: 0 }.into(),
+        ]);
+        assert_eq!(
+            outer.compose(&inner),
+            LineMap(vec![
+                Origin { line: 2, column: 6 }.into(),
+                Origin { line: 0, column: 0 }.into(),
+            ])
+        );
+    }
+
+    #[test]
+    fn should_compose_pieces() {
+        let mut outer = MappedText::default();
+        let dependency = Origin { line: 3, column: 9 };
+        outer.push_header(
+            "g/x",
+            &[("g/a", dependency, None)],
+            Origin { line: 4, column: 2 },
+        );
+        let inner = LineMap(vec![Line {
+            origin: Origin { line: 0, column: 0 },
+            pieces: vec![Piece {
+                start: 5,
+                origin: Origin { line: 0, column: 5 },
+                pattern: Some("g/*".to_string()),
+            }],
+        }]);
+        let composed = outer.map.compose(&inner);
+        let at = |column| Origin { line: 0, column };
+        assert_eq!(composed.locate(at(1)), Origin { line: 4, column: 3 });
+        assert_eq!(
+            composed.locate(at(6)),
+            Origin {
+                line: 3,
+                column: 10
+            }
+        );
+        assert_eq!(composed.pattern(at(6)), Some("g/*"));
+        // a line that is not rewritten keeps the pieces it comes from
+        let shifted = outer
+            .map
+            .compose(&LineMap(vec![Origin { line: 0, column: 4 }.into()]));
+        assert_eq!(shifted.locate(at(1)), dependency);
+    }
+
+    fn dependencies(content: &str) -> Vec<(String, usize, Option<String>)> {
+        let sources = fixtures::sources(content);
+        let text = fixtures::desugar(&sources);
+        let mut dependencies = Vec::new();
+        for node in crate::syntax::nodes(&text, &sources) {
+            if let crate::node::Node::Segment {
+                name: "g/x",
+                dependencies: deps,
+                ..
+            } = node
+            {
+                for dep in deps {
+                    let pattern = dep.pattern.map(str::to_string);
+                    dependencies.push((dep.name.to_string(), dep.location.column, pattern));
+                }
+            }
+        }
+        dependencies
+    }
+
+    #[test]
+    fn should_locate_rewritten_dependency() {
+        assert_eq!(
+            dependencies(
+                "g/: /dep
+  x: lib other/lib
+"
+            ),
+            [
+                ("g".to_string(), 1, None),
+                ("dep".to_string(), 5, None),
+                ("g/lib".to_string(), 6, None),
+                ("g/other/lib".to_string(), 10, None),
+            ]
+        );
+    }
+
+    #[test]
+    fn should_find_pattern_of_expanded_dependency() {
+        let pattern = Some("p/*/build".to_string());
+        assert_eq!(
+            dependencies("p/a/build:\np/b/build:\ng/:\n  x: build /p/*/build\n"),
+            [
+                ("g".to_string(), 1, None),
+                ("g/build".to_string(), 6, None),
+                ("p/a/build".to_string(), 12, pattern.clone()),
+                ("p/b/build".to_string(), 12, pattern),
+            ]
+        );
+    }
+}
@@ -0,0 +1,109 @@
+// Suggestions offered for a mistyped target
+const MAX_SUGGESTIONS: usize = 3;
+
+/// Edits turning one text into the other, a swap of adjacent characters
+/// counting as one edit (the optimal string alignment distance)
+fn distance(a: &str, b: &str) -> usize {
+    let a: Vec<char> = a.chars().collect();
+    let b: Vec<char> = b.chars().collect();
+    let mut d = vec![vec![0; b.len() + 1]; a.len() + 1];
+    for (i, row) in d.iter_mut().enumerate() {
+        row[0] = i;
+    }
+    d[0] = (0..=b.len()).collect();
+    for i in 1..=a.len() {
+        for j in 1..=b.len() {
+            let cost = usize::from(a[i - 1] != b[j - 1]);
+            d[i][j] = (d[i - 1][j] + 1)
+                .min(d[i][j - 1] + 1)
+                .min(d[i - 1][j - 1] + cost);
+            if i > 1 && j > 1 && a[i - 1] == b[j - 2] && a[i - 2] == b[j - 1] {
+                d[i][j] = d[i][j].min(d[i - 2][j - 2] + 1);
+            }
+        }
+    }
+    d[a.len()][b.len()]
+}
+
+/// Edits tolerated in a name, replacing every character of a short one is not
+fn tolerance(name: &str) -> usize {
+    let length = name.chars().count();
+    (length / 3).max(1).min(length.saturating_sub(1))
+}
+
+/// Trailing path components of the name, as many as the target has
+fn tail<'a>(name: &'a str, target: &str) -> Option<&'a str> {
+    let components = target.split('/').count();
+    let mut start = name.len();
+    for _ in 0..components {
+        start = name[..start].rfind('/')?;
+    }
+    Some(&name[start + 1..])
+}
+
+/// Names closest to the unknown target, best first.
+/// A name whose trailing components are close to the target is suggested too,
+/// e.g. `app/ppp/build` for `ppp/build`, ranked by the edits of the components
+/// and then by the length of the omitted group.
+pub fn similar<'a>(target: &str, names: impl IntoIterator<Item = &'a str>) -> Vec<&'a str> {
+    let mut ranked: Vec<((usize, usize), &str)> = names
+        .into_iter()
+        .filter_map(|name| {
+            let full = distance(target, name);
+            let tail = tail(name, target).map(|tail| distance(target, tail));
+            let rank = match tail {
+                Some(tail) if tail < full => (tail, name.len() - target.len().min(name.len())),
+                _ => (full, 0),
+            };
+            (rank.0 <= tolerance(target)).then_some((rank, name))
+        })
+        .collect();
+    ranked.sort();
+    ranked.dedup_by_key(|(_, name)| *name);
+    ranked
+        .into_iter()
+        .take(MAX_SUGGESTIONS)
+        .map(|(_, name)| name)
+        .collect()
+}
+
+/// Whether the target is only a group prefix of some of the names
+pub fn is_group_prefix<'a>(target: &str, mut names: impl Iterator<Item = &'a str>) -> bool {
+    let target = target.trim_end_matches('/');
+    !target.is_empty()
+        && names.any(|name| {
+            name.strip_prefix(target)
+                .is_some_and(|rest| rest.starts_with('/'))
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn should_measure_distance() {
+        assert_eq!(distance("build", "biuld"), 1);
+        assert_eq!(distance("build", "buid"), 1);
+        assert_eq!(distance("", "abc"), 3);
+        assert_eq!(distance("kitten", "sitting"), 3);
+    }
+
+    #[test]
+    fn should_suggest_close_and_group_prefixed_names() {
+        let names = ["build", "bundle", "app/ppp/build", "app/ppp/deps", "test"];
+        assert_eq!(similar("buld", names), ["build", "app/ppp/build"]);
+        assert_eq!(similar("ppp/build", names), ["app/ppp/build"]);
+        assert_eq!(similar("ppp/dpes", names), ["app/ppp/deps"]);
+        assert_eq!(similar("bulid", names), ["build", "app/ppp/build"]);
+        assert!(similar("deploy", names).is_empty());
+    }
+
+    #[test]
+    fn should_detect_group_prefix() {
+        let names = ["app/ppp/build", "apps"];
+        assert!(is_group_prefix("app/ppp", names.into_iter()));
+        assert!(is_group_prefix("app/", names.into_iter()));
+        assert!(!is_group_prefix("ap", names.into_iter()));
+    }
+}
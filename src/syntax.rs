@@ -1,6 +1,71 @@
+use super::error::MkError;
 use super::glob_pattern;
 use super::groups;
+use super::include;
+use super::node::Node;
+use super::parameters;
+use super::segments_scanner::SegmentsScanner;
+use super::source::{Locator, MappedText, Sources};
+use super::variables;
 
-pub fn desugar(content: &str) -> String {
-    glob_pattern::desugar(groups::desugar(content).as_str())
+#[derive(Debug, Default, Clone, Copy)]
+pub struct Options<'a> {
+    /// Values overriding the variables of the mkfiles
+    pub variables: &'a [(String, String)],
+    /// Targets that may call parameterized segments
+    pub targets: &'a [&'a str],
+}
+
+/// Content of the sources with the syntax sugar rewritten into plain segments
+pub fn desugar<'a>(sources: &'a Sources, options: &Options) -> Result<MappedText, MkError<'a>> {
+    let included =
+        variables::desugar(include::desugar(sources)?, options.variables).map_err(|e| {
+            MkError::MultilineVariable {
+                name: e.name,
+                location: sources.locate(e.origin.line, e.origin.column),
+            }
+        })?;
+    let MappedText { content, map } = groups::desugar(&included.content).map_err(|e| {
+        let origin = included.map.locate_indented(e.origin);
+        MkError::BadGroupIndentation {
+            group: e.group,
+            location: sources.locate(origin.line, origin.column),
+        }
+    })?;
+    let map = included.map.compose_indented(&map);
+    let text = parameters::desugar(MappedText { content, map }, options.targets);
+    glob_pattern::desugar(&text).map_err(|e| MkError::BadGlob {
+        pattern: e.pattern,
+        reason: e.reason,
+        location: Some(Locator::new(&text.content, &text.map, sources).locate(e.offset)),
+    })
+}
+
+/// Nodes of the desugared content with their locations in the sources
+pub fn nodes<'a>(content: &'a MappedText, sources: &'a Sources) -> Vec<Node<'a>> {
+    let locator = Locator::new(&content.content, &content.map, sources);
+    SegmentsScanner::new(&content.content)
+        .map(|mut node| {
+            node.locate(&locator);
+            node
+        })
+        .collect()
+}
+
+#[cfg(test)]
+pub mod fixtures {
+    use crate::source::{MappedText, SourceFile, Sources};
+
+    /// Sources of a single input file named `mkfile`
+    pub fn sources(content: &str) -> Sources {
+        Sources::new(vec![SourceFile {
+            name: "mkfile".to_string(),
+            content: content.to_string(),
+        }])
+    }
+
+    /// Content of valid sources desugared with the default options
+    pub fn desugar(sources: &Sources) -> MappedText {
+        super::desugar(sources, &Default::default()).unwrap()
+    }
 }
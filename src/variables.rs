@@ -0,0 +1,203 @@
+use std::collections::HashMap;
+use std::env;
+
+use super::source::{LineMap, MappedText, Origin};
+
+/// Prefix of the environment variables overriding the definitions
+pub const ENV_PREFIX: &str = "MK_";
+
+/// Value of a variable spanning several lines,
+/// which would shift the lines following its substitutions
+pub struct MultilineValueError {
+    pub name: String,
+    pub origin: Origin,
+}
+
+pub fn is_name(name: &str) -> bool {
+    let mut chars = name.chars();
+    chars
+        .next()
+        .is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+/// Name and value of a `NAME := value` line
+pub fn definition(line: &str) -> Option<(&str, &str)> {
+    let (name, value) = line.split_once(":=")?;
+    let name = name.trim_end();
+    is_name(name).then(|| (name, value.trim()))
+}
+
+/// `NAME=value` of the command line
+pub fn parse_override(text: &str) -> Result<(String, String), String> {
+    match text.split_once('=') {
+        Some((name, value)) if value.contains('\n') => {
+            Err(format!("the value of `{name}` spans several lines"))
+        }
+        Some((name, value)) if is_name(name) => Ok((name.to_string(), value.to_string())),
+        _ => Err(format!("expected NAME=value, got `{text}`")),
+    }
+}
+
+/// Text with `{{NAME}}` replaced by the value of the variable,
+/// undefined variables are left as they are
+pub fn substitute(text: &str, variables: &HashMap<&str, String>) -> String {
+    let mut out = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(start) = rest.find("{{") {
+        let Some(len) = rest[start + 2..].find("}}") else {
+            break;
+        };
+        let end = start + len + 4;
+        out.push_str(&rest[..start]);
+        match variables.get(rest[start + 2..end - 2].trim()) {
+            Some(value) => out.push_str(value),
+            None => out.push_str(&rest[start..end]),
+        }
+        rest = &rest[end..];
+    }
+    out.push_str(rest);
+    out
+}
+
+/// Definition lines removed and the variables substituted in the other lines.
+/// A definition starts a line of its file, the map of `text` holding
+/// the indentation added to each line by the include directives.
+/// Values given on the command line take precedence over the environment
+/// variables prefixed with `MK_`, which take precedence over the definitions.
+pub fn desugar(
+    text: MappedText,
+    overrides: &[(String, String)],
+) -> Result<MappedText, MultilineValueError> {
+    desugar_with(text, overrides, |name| env::var(name).ok())
+}
+
+fn desugar_with(
+    text: MappedText,
+    overrides: &[(String, String)],
+    environment: impl Fn(&str) -> Option<String>,
+) -> Result<MappedText, MultilineValueError> {
+    let lines: Vec<_> = text
+        .content
+        .split('\n')
+        .enumerate()
+        .map(|(i, line)| {
+            let added = text.map.get(i).column;
+            (i, line, line.get(added..).and_then(definition))
+        })
+        .collect();
+    if overrides.is_empty() && lines.iter().all(|(_, _, d)| d.is_none()) {
+        return Ok(text);
+    }
+    let mut variables: HashMap<&str, String> = HashMap::new();
+    for (i, _, definition) in &lines {
+        let Some((name, value)) = definition else {
+            continue;
+        };
+        // later definitions see the overridden values
+        let value = match overrides.iter().find(|(n, _)| n == name) {
+            Some((_, value)) => value.clone(),
+            None => match environment(&format!("{ENV_PREFIX}{name}")) {
+                Some(value) if value.contains('\n') => {
+                    return Err(MultilineValueError {
+                        name: name.to_string(),
+                        origin: Origin {
+                            line: text.map.get(*i).line,
+                            column: 0,
+                        },
+                    });
+                }
+                Some(value) => value,
+                None => substitute(value, &variables),
+            },
+        };
+        variables.insert(name, value);
+    }
+    for (name, value) in overrides {
+        variables.insert(name, value.clone());
+    }
+    let mut content = Vec::with_capacity(lines.len());
+    let mut map = LineMap::default();
+    for (i, line, definition) in lines {
+        if definition.is_none() {
+            content.push(substitute(line, &variables));
+            map.push(text.map.get(i));
+        }
+    }
+    Ok(MappedText {
+        content: content.join("\n"),
+        map,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn desugar_text(content: &str, overrides: &[(&str, &str)]) -> String {
+        let overrides: Vec<_> = overrides
+            .iter()
+            .map(|(n, v)| (n.to_string(), v.to_string()))
+            .collect();
+        desugar_with(MappedText::new(content), &overrides, |_| None)
+            .ok()
+            .unwrap()
+            .content
+    }
+
+    #[test]
+    fn should_parse_definition() {
+        assert_eq!(definition("OUT := build/out"), Some(("OUT", "build/out")));
+        assert_eq!(definition("_x:=1"), Some(("_x", "1")));
+        assert_eq!(definition("    OUT := x"), None);
+        assert_eq!(definition("a b := x"), None);
+    }
+
+    #[test]
+    fn should_substitute_defined_variables() {
+        assert_eq!(
+            desugar_text(
+                "MK_TEST_DIR := out\nMK_TEST_BIN := {{ MK_TEST_DIR }}/bin\nbuild:\n    cp app {{MK_TEST_BIN}} {{.Unknown}} {{ OTHER }}",
+                &[]
+            ),
+            "build:\n    cp app out/bin {{.Unknown}} {{ OTHER }}"
+        );
+    }
+
+    #[test]
+    fn should_override_definitions() {
+        assert_eq!(
+            desugar_text(
+                "MK_TEST_ENV := dev\nMK_TEST_URL := {{MK_TEST_ENV}}.example.com\n{{MK_TEST_URL}} {{MK_TEST_NEW}}",
+                &[("MK_TEST_ENV", "prod"), ("MK_TEST_NEW", "new")]
+            ),
+            "prod.example.com new"
+        );
+    }
+
+    #[test]
+    fn should_keep_origins_of_remaining_lines() {
+        let text = MappedText::new("A := 1\nfoo:\n    echo {{A}}");
+        let text = desugar_with(text, &[], |_| None).ok().unwrap();
+        assert_eq!(text.content, "foo:\n    echo 1");
+        assert_eq!(text.map.get(0).line, 1);
+        assert_eq!(text.map.get(1).line, 2);
+    }
+
+    #[test]
+    fn should_override_definitions_with_prefixed_environment() {
+        let environment = |name: &str| match name {
+            "MK_OUT" => Some("env".to_string()),
+            "OUT" => Some("unprefixed".to_string()),
+            "MK_LINES" => Some("a\nb".to_string()),
+            _ => None,
+        };
+        let text = MappedText::new("OUT := out\n{{OUT}}");
+        let text = desugar_with(text, &[], environment).ok().unwrap();
+        assert_eq!(text.content, "env");
+        let text = MappedText::new("foo:\nLINES := x\n");
+        let error = desugar_with(text, &[], environment).err().unwrap();
+        assert_eq!((error.name.as_str(), error.origin.line), ("LINES", 1));
+        assert!(parse_override("LINES=a\nb").is_err());
+    }
+}